@@ -0,0 +1,8 @@
+create table releases (
+    version               integer primary key,
+    released_at           timestamp not null,
+    changelog             text    not null default '',
+    file                  text    not null,
+    min_supported_version integer not null default 0,
+    mandatory             boolean not null default false
+);
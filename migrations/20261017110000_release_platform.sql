@@ -0,0 +1,2 @@
+-- null means the release is offered to every platform
+alter table releases add column platform text;
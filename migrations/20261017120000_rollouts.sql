@@ -0,0 +1,10 @@
+alter table releases add column channel text not null default 'stable'
+    check (channel in ('stable', 'beta'));
+
+-- releases without a rollout row are offered to every client
+create table rollouts (
+    version    integer primary key references releases (version),
+    percentage integer not null default 100 check (percentage between 0 and 100),
+    paused     boolean not null default false,
+    updated_at timestamp not null
+);
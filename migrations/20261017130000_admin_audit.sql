@@ -0,0 +1,8 @@
+create table admin_audit (
+    id      integer primary key autoincrement,
+    time    timestamp not null,
+    admin   text not null,
+    action  text not null,
+    -- json
+    details text not null
+);
@@ -0,0 +1 @@
+create index time_idx on downloads (time);
@@ -0,0 +1,21 @@
+create index online_users_time_idx on online_users (time);
+
+-- time is the start of the hour (utc), peak_time the sample with the highest count
+create table online_users_hourly (
+    time      timestamp primary key,
+    min       integer   not null,
+    avg       real      not null,
+    max       integer   not null,
+    peak_time timestamp not null,
+    samples   integer   not null
+);
+
+-- time is the start of the day (utc)
+create table online_users_daily (
+    time      timestamp primary key,
+    min       integer   not null,
+    avg       real      not null,
+    max       integer   not null,
+    peak_time timestamp not null,
+    samples   integer   not null
+);
@@ -0,0 +1,8 @@
+-- online users per client version, version is null for clients not reporting it
+create table online_versions (
+    time    timestamp not null,
+    version integer,
+    count   integer   not null
+);
+
+create index online_versions_time_idx on online_versions (time);
@@ -0,0 +1,9 @@
+-- online presence saved across restarts, replaced as a whole on every save.
+-- install_id is null for old clients, which are tracked by ip
+create table presence (
+    install_id text unique,
+    ip         text      not null,
+    version    integer,
+    session    text,
+    seen_at    timestamp not null
+);
@@ -0,0 +1,14 @@
+-- live streams seen by the live providers, by youtube video id
+create table streams (
+    video_id     text primary key,
+    channel_id   text      not null,
+    title        text      not null,
+    -- start time reported by youtube
+    started_at   timestamp not null,
+    first_seen   timestamp not null,
+    last_seen    timestamp not null,
+    -- null while no provider reported viewers
+    peak_viewers integer
+);
+
+create index streams_last_seen_idx on streams (last_seen);
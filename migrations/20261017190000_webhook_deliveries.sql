@@ -0,0 +1,16 @@
+-- webhook delivery attempts, webhook is the name from the config
+create table webhook_deliveries (
+    id        integer primary key autoincrement,
+    time      timestamp not null,
+    webhook   text      not null,
+    video_id  text      not null,
+    -- live or offline
+    event     text      not null,
+    attempt   integer   not null,
+    -- null when no response came back
+    status    integer,
+    error     text,
+    delivered boolean   not null
+);
+
+create index webhook_deliveries_video_idx on webhook_deliveries (webhook, video_id, event);
@@ -0,0 +1,22 @@
+-- time is the start of the hour (utc), avg counts samples without the version as 0 so the
+-- versions of an hour add up to the online users
+create table online_versions_hourly (
+    time    timestamp not null,
+    version integer,
+    avg     real      not null,
+    max     integer   not null,
+    samples integer   not null
+);
+
+create index online_versions_hourly_time_idx on online_versions_hourly (time);
+
+-- time is the start of the day (utc)
+create table online_versions_daily (
+    time    timestamp not null,
+    version integer,
+    avg     real      not null,
+    max     integer   not null,
+    samples integer   not null
+);
+
+create index online_versions_daily_time_idx on online_versions_daily (time);
@@ -0,0 +1,403 @@
+use crate::config::{AdminConfig, AdminToken};
+use crate::file_host::{FileHost, ManifestEntry};
+use crate::releases::{NewRelease, ReleaseCatalog, Rollout};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::web::{Data, ServiceConfig};
+use actix_web::{delete, post, put, web, FromRequest, HttpRequest, HttpResponse};
+use anyhow::Context;
+use futures::future::{ready, Ready};
+use futures::StreamExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+pub fn configure_service(admin: AdminConfig, config: &mut ServiceConfig) {
+    config
+        .app_data(Data::new(AdminTokens(admin.tokens)))
+        .app_data(Data::new(UploadLimit(admin.max_upload_mb * 1024 * 1024)))
+        .service(upload)
+        .service(register_file)
+        .service(retire_file)
+        .service(publish_release)
+        .service(set_rollout);
+}
+
+struct AdminTokens(Vec<AdminToken>);
+
+/// Maximum upload size in bytes.
+struct UploadLimit(u64);
+
+/// Authenticated admin, extracted from the `Authorization: Bearer <token>` header.
+pub struct Admin {
+    pub name: String,
+}
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> actix_web::Result<Admin> {
+    let tokens = req
+        .app_data::<Data<AdminTokens>>()
+        .expect("Admin tokens must be registered!");
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(unauthorized)?;
+    tokens
+        .0
+        .iter()
+        .find(|token| constant_time_eq(token.token.as_bytes(), presented.as_bytes()))
+        .map(|token| Admin {
+            name: token.name.clone(),
+        })
+        .ok_or_else(unauthorized)
+}
+
+fn unauthorized() -> actix_web::Error {
+    InternalError::from_response(
+        "unauthorized",
+        HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .finish(),
+    )
+    .into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn audit(pg: &Pool<Sqlite>, admin: &Admin, action: &str, details: serde_json::Value) {
+    info!("Admin '{}' performed {action}: {details}", admin.name);
+    let result = sqlx::query(
+        "insert into admin_audit (time, admin, action, details) \
+         values (datetime('now'), ?, ?, ?);",
+    )
+    .bind(&admin.name)
+    .bind(action)
+    .bind(details.to_string())
+    .execute(pg)
+    .await;
+    if let Err(err) = result {
+        error!(
+            "Could not insert admin audit record ({action} by {}): {err:#}",
+            admin.name
+        );
+    }
+}
+
+fn bad_request(err: anyhow::Error) -> actix_web::Error {
+    actix_web::error::ErrorBadRequest(format!("{err:#}"))
+}
+
+/// Accepts only plain relative paths, so a leaked token can't touch files outside the host dir.
+fn is_plain_relative(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_))) && path.components().count() > 0
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadResponse {
+    path: PathBuf,
+    sha256: String,
+    size: u64,
+}
+
+/// Stores the request body next to the file manifest. Existing files are never overwritten.
+#[put("/uploads/{file_name}")]
+async fn upload(
+    admin: Admin,
+    file_name: web::Path<String>,
+    payload: web::Payload,
+    limit: Data<UploadLimit>,
+    file_host: Data<FileHost>,
+    pg: Data<Pool<Sqlite>>,
+) -> actix_web::Result<HttpResponse> {
+    let file_name = PathBuf::from(file_name.into_inner());
+    if !is_plain_relative(&file_name) || file_name.components().count() != 1 {
+        return Err(actix_web::error::ErrorBadRequest("invalid file name"));
+    }
+    let path = file_host.base_dir().join(&file_name);
+    let partial_path = partial_path(&path);
+    if path.exists() || partial_path.exists() {
+        return Err(actix_web::error::ErrorConflict("file already exists"));
+    }
+
+    let file = web::block({
+        let partial_path = partial_path.clone();
+        move || File::create_new(partial_path)
+    })
+    .await?
+    .inspect_err(|err| error!("Could not create {}: {err}", partial_path.display()))
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let stored = async {
+        let received = receive(payload, file, limit.0).await?;
+        web::block({
+            let partial_path = partial_path.clone();
+            let path = path.clone();
+            move || std::fs::rename(partial_path, path)
+        })
+        .await?
+        .inspect_err(|err| error!("Could not move upload to {}: {err}", path.display()))
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        Ok::<_, actix_web::Error>(received)
+    };
+    let (sha256, size) = match stored.await {
+        Ok(received) => received,
+        Err(err) => {
+            let _ = web::block(move || std::fs::remove_file(partial_path)).await;
+            return Err(err);
+        }
+    };
+
+    let response = UploadResponse {
+        path: file_name,
+        sha256,
+        size,
+    };
+    audit(&pg, &admin, "upload", json!(response)).await;
+    Ok(HttpResponse::Created().json(response))
+}
+
+/// Uploads are received as `<file name>.part`, so `foo.jar` and `foo.zip` never share one.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".part");
+    PathBuf::from(partial_path)
+}
+
+/// Streams the payload into the file, returns its hex encoded SHA-256 and size.
+async fn receive(
+    mut payload: web::Payload,
+    mut file: File,
+    max_size: u64,
+) -> actix_web::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                "upload must not be larger than {max_size} bytes"
+            )));
+        }
+        hasher.update(&chunk);
+        file = web::block(move || file.write_all(&chunk).map(|_| file))
+            .await?
+            .inspect_err(|err| error!("Could not write upload: {err}"))
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterFile {
+    name: String,
+    /// Relative to the manifest directory.
+    path: PathBuf,
+    content_type: Option<String>,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    default: bool,
+}
+
+#[post("/files")]
+async fn register_file(
+    admin: Admin,
+    file: web::Json<RegisterFile>,
+    file_host: Data<FileHost>,
+    pg: Data<Pool<Sqlite>>,
+) -> actix_web::Result<HttpResponse> {
+    let file = file.into_inner();
+    if !is_plain_relative(&file.path) {
+        return Err(actix_web::error::ErrorBadRequest("invalid file path"));
+    }
+    let details = json!(file);
+    file_host
+        .register(ManifestEntry {
+            name: file.name,
+            path: file.path,
+            content_type: file.content_type,
+            description: file.description,
+            default: file.default,
+        })
+        .await
+        .map_err(bad_request)?;
+    audit(&pg, &admin, "register_file", details).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/files/{name}")]
+async fn retire_file(
+    admin: Admin,
+    name: web::Path<String>,
+    file_host: Data<FileHost>,
+    pg: Data<Pool<Sqlite>>,
+) -> actix_web::Result<HttpResponse> {
+    file_host.retire(&name).await.map_err(bad_request)?;
+    audit(&pg, &admin, "retire_file", json!({ "name": *name })).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/releases")]
+async fn publish_release(
+    admin: Admin,
+    release: web::Json<NewRelease>,
+    catalog: Data<ReleaseCatalog>,
+    file_host: Data<FileHost>,
+    pg: Data<Pool<Sqlite>>,
+) -> actix_web::Result<HttpResponse> {
+    if release.rollout_percentage > 100 {
+        return Err(actix_web::error::ErrorBadRequest(
+            "rollout percentage must be at most 100",
+        ));
+    }
+    if file_host.integrity(&release.file).is_none() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "release file is not hosted",
+        ));
+    }
+    catalog
+        .publish(&release)
+        .await
+        .context("Could not publish release")
+        .map_err(bad_request)?;
+    audit(&pg, &admin, "publish_release", json!(*release)).await;
+    Ok(HttpResponse::Created().finish())
+}
+
+#[put("/releases/{version}/rollout")]
+async fn set_rollout(
+    admin: Admin,
+    version: web::Path<u32>,
+    rollout: web::Json<Rollout>,
+    catalog: Data<ReleaseCatalog>,
+    pg: Data<Pool<Sqlite>>,
+) -> actix_web::Result<HttpResponse> {
+    if rollout.percentage > 100 {
+        return Err(actix_web::error::ErrorBadRequest(
+            "rollout percentage must be at most 100",
+        ));
+    }
+    let updated = catalog
+        .set_rollout(*version, &rollout)
+        .await
+        .inspect_err(|err| error!("Could not set rollout: {err:#}"))
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if !updated {
+        return Err(actix_web::error::ErrorNotFound("release not found"));
+    }
+    let details = json!({ "version": *version, "rollout": *rollout });
+    audit(&pg, &admin, "set_rollout", details).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    pub async fn test_authenticate() {
+        let tokens = vec![AdminToken {
+            name: String::from("makin"),
+            token: "x".repeat(32),
+        }];
+        let app_data = Data::new(AdminTokens(tokens));
+        let req = TestRequest::default()
+            .app_data(Data::clone(&app_data))
+            .insert_header((AUTHORIZATION, format!("Bearer {}", "x".repeat(32))))
+            .to_http_request();
+        assert_eq!(authenticate(&req).unwrap().name, "makin");
+
+        for header in ["Bearer nope", "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"] {
+            let req = TestRequest::default()
+                .app_data(Data::clone(&app_data))
+                .insert_header((AUTHORIZATION, header))
+                .to_http_request();
+            assert!(authenticate(&req).is_err());
+        }
+        let req = TestRequest::default().app_data(app_data).to_http_request();
+        assert!(authenticate(&req).is_err());
+    }
+
+    #[test]
+    pub fn test_plain_relative() {
+        assert!(is_plain_relative(Path::new("BuzkaaClicker-v17.rar")));
+        assert!(is_plain_relative(Path::new("builds/BuzkaaClicker-v17.rar")));
+        assert!(!is_plain_relative(Path::new("../db.sqlite")));
+        assert!(!is_plain_relative(Path::new("/etc/passwd")));
+        assert!(!is_plain_relative(Path::new("")));
+    }
+
+    #[actix_web::test]
+    pub async fn test_upload() {
+        let dir = std::env::temp_dir().join(format!("bclicker-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pg = db::test_pool().await;
+        let file_host = FileHost::load(Pool::clone(&pg), dir.join("manifest.toml")).unwrap();
+        let token = "x".repeat(32);
+        let admin = AdminConfig {
+            tokens: vec![AdminToken {
+                name: String::from("makin"),
+                token: token.clone(),
+            }],
+            max_upload_mb: 1,
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(file_host))
+                .app_data(Data::new(pg))
+                .configure(|config| configure_service(admin, config)),
+        )
+        .await;
+        let upload_request = |name: &str, size: usize| {
+            TestRequest::put()
+                .uri(&format!("/uploads/{name}"))
+                .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+                .set_payload(vec![0; size])
+                .to_request()
+        };
+
+        for name in ["BuzkaaClicker.jar", "BuzkaaClicker.zip"] {
+            let res = test::call_service(&app, upload_request(name, 1024)).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        assert_eq!(
+            std::fs::metadata(dir.join("BuzkaaClicker.zip"))
+                .unwrap()
+                .len(),
+            1024
+        );
+        let res = test::call_service(&app, upload_request("BuzkaaClicker.jar", 1)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res =
+            test::call_service(&app, upload_request("BuzkaaClicker.rar", 1024 * 1024 + 1)).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.join("BuzkaaClicker.rar").exists());
+        assert!(!dir.join("BuzkaaClicker.rar.part").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
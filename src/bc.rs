@@ -1,29 +1,48 @@
-use crate::cache::Memoized;
+use crate::cache::{Memoized, MemoizedMap};
+use crate::date_range::{self, MAX_CACHED_QUERIES};
+use crate::download_stats;
+use crate::events;
+use crate::online_stats;
 use crate::online_users;
-use crate::online_users::OnlineUsersData;
+use crate::online_users::{ChartFormat, ChartQuery, Heartbeat, MinuteRetention, OnlineUsersData};
+use crate::online_versions;
+use crate::releases;
 use actix_web::http::header::ContentType;
 use actix_web::web::{Data, ServiceConfig};
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
 use anyhow::Context;
 use log::{debug, error};
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Row, Sqlite};
 use std::time::Duration;
 
 pub fn configure_service(
     bc_version: Version,
+    minute_retention: MinuteRetention,
     chart_json: &Data<Memoized<ChartJson>>,
+    chart_by_query: &Data<MemoizedMap<ChartQuery, ChartJson>>,
     config: &mut ServiceConfig,
 ) {
     config
         .app_data(Data::clone(chart_json))
+        .app_data(Data::clone(chart_by_query))
         .app_data(Data::new(bc_version))
+        .app_data(Data::new(minute_retention))
         .service(get_chart)
         .service(get_download_count)
+        .service(download_stats::get_stats)
+        .service(online_stats::get_stats)
+        .service(events::stream)
         .service(
             web::resource(vec!["/online-users", "/onlineUsers"])
                 .route(web::get().to(get_online_users_count)),
         )
-        .service(version);
+        .service(online_versions::get_online_versions)
+        .service(online_versions::get_history)
+        .service(releases::get_version)
+        .service(releases::get_latest)
+        .service(releases::get_since)
+        .service(releases::update_check);
 }
 
 #[derive(Copy, Clone)]
@@ -34,71 +53,162 @@ pub async fn get_online_users_count(online_users: OnlineUsersData) -> impl Respo
     format!("{count}")
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatRequest {
+    /// Random id generated once per install.
+    install_id: String,
+    version: u32,
+    /// Random id generated once per clicker start.
+    session_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HeartbeatResponse {
+    online: u32,
+}
+
+/// Ids are generated by the clients, so only accept something that looks like one.
+fn is_valid_client_id(id: &str) -> bool {
+    (8..=64).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Marks the install online for the next 70 seconds, clients are expected to call it more often.
+/// Served at POST /heartbeat behind a rate limiter, see main.
+pub async fn heartbeat(
+    req: HttpRequest,
+    heartbeat: web::Json<HeartbeatRequest>,
+    online_users: OnlineUsersData,
+) -> actix_web::Result<impl Responder> {
+    let heartbeat = heartbeat.into_inner();
+    if !is_valid_client_id(&heartbeat.install_id) {
+        return Err(actix_web::error::ErrorBadRequest("invalid install id"));
+    }
+    if let Some(session_id) = &heartbeat.session_id {
+        if !is_valid_client_id(session_id) {
+            return Err(actix_web::error::ErrorBadRequest("invalid session id"));
+        }
+    }
+    let mut online_users = online_users.lock().expect("Online users poisoned!");
+    let accepted = online_users.heartbeat(Heartbeat {
+        install_id: heartbeat.install_id,
+        ip: releases::real_ip(&req),
+        version: heartbeat.version,
+        session: heartbeat.session_id,
+    });
+    if !accepted {
+        return Err(actix_web::error::ErrorTooManyRequests(
+            "too many installs online from this ip",
+        ));
+    }
+    Ok(web::Json(HeartbeatResponse {
+        online: online_users.count(),
+    }))
+}
+
 #[derive(Clone)]
-pub struct ChartJson(Option<String>);
+pub struct ChartJson(String);
 
 impl ChartJson {
     pub async fn memoized(pg: Pool<Sqlite>) -> Memoized<Self> {
-        Memoized::new(Duration::from_secs(60), move || {
-            let pg = Pool::clone(&pg);
-            async move {
-                debug!("Generating new chart json...");
-                let result = online_users::get_chart_data(&pg)
-                    .await
-                    .context("Could not get chart data from db")
-                    .and_then(|data| {
-                        serde_json::to_string(&data).context("Could not serialize chart data")
-                    });
-                Self(match result {
-                    Ok(json) => Some(json),
-                    Err(err) => {
-                        error!("Could not get chart data from db: {err:#}");
-                        None
+        Memoized::builder(Duration::from_secs(60))
+            .hard_expiry(Duration::from_secs(10 * 60))
+            .refresh_every(Duration::from_secs(50), Duration::from_secs(10))
+            .build(move || {
+                let pg = Pool::clone(&pg);
+                async move {
+                    debug!("Generating new chart json...");
+                    let data = online_users::get_chart_data(&pg)
+                        .await
+                        .context("Could not get chart data from db")?;
+                    let json =
+                        serde_json::to_string(&data).context("Could not serialize chart data")?;
+                    Ok(Self(json))
+                }
+            })
+            .await
+    }
+
+    /// Charts for custom ranges and buckets.
+    pub fn memoized_by_query(
+        pg: Pool<Sqlite>,
+        minute_retention: MinuteRetention,
+    ) -> MemoizedMap<ChartQuery, Self> {
+        MemoizedMap::builder(Duration::from_secs(60), MAX_CACHED_QUERIES)
+            .ttl_by(|query: &ChartQuery| date_range::cache_ttl(query.to))
+            .build(move |query| {
+                let pg = Pool::clone(&pg);
+                async move {
+                    debug!("Generating new chart json for {query:?}...");
+                    let json = match query.format {
+                        ChartFormat::Labels => {
+                            let data =
+                                online_users::get_bucketed_chart_data(&pg, query, minute_retention)
+                                    .await?;
+                            serde_json::to_string(&data)
+                        }
+                        ChartFormat::Iso => {
+                            let data =
+                                online_users::get_iso_chart_data(&pg, query, minute_retention)
+                                    .await?;
+                            serde_json::to_string(&data)
+                        }
                     }
-                })
-            }
-        })
-        .await
+                    .context("Could not serialize chart data")?;
+                    Ok(Self(json))
+                }
+            })
     }
 }
 
 #[get("/online-list")]
-pub async fn get_chart(chart: web::Data<Memoized<ChartJson>>) -> actix_web::Result<impl Responder> {
-    let chart_data = chart
-        .get()
-        .await
-        .0
-        .ok_or_else(|| actix_web::error::ErrorInternalServerError("no data"))?
-        .to_string();
+pub async fn get_chart(
+    query: web::Query<ChartQuery>,
+    minute_retention: web::Data<MinuteRetention>,
+    chart: web::Data<Memoized<ChartJson>>,
+    chart_by_query: web::Data<MemoizedMap<ChartQuery, ChartJson>>,
+) -> actix_web::Result<impl Responder> {
+    let chart = if query.is_default() {
+        chart
+            .get()
+            .await
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("no data"))?
+    } else {
+        query
+            .resolve(**minute_retention)
+            .map_err(|err| actix_web::error::ErrorBadRequest(format!("{err:#}")))?;
+        chart_by_query.get(*query).await.map_err(|err| {
+            error!("Could not get chart for {query:?}: {err:#}");
+            actix_web::error::ErrorInternalServerError("could not get chart")
+        })?
+    };
     Ok(HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(chart_data))
+        .insert_header(chart.age_header())
+        .body(chart.value.0))
 }
 
 #[derive(Clone)]
-pub struct DownloadCount(Option<u64>);
+pub struct DownloadCount(u64);
 
 impl DownloadCount {
     pub async fn memoized(pg: Pool<Sqlite>) -> Memoized<Self> {
-        Memoized::new(Duration::from_secs(60), move || {
-            let pg = Pool::clone(&pg);
-            async move {
-                let row_result = sqlx::query(
-                    "SELECT COUNT(DISTINCT ip) FROM downloads WHERE file='BClickerDownloader'",
-                )
-                .fetch_one(&pg)
-                .await
-                .context("Could not select data");
-                Self(match row_result {
-                    Ok(row) => Some(row.get::<i64, _>(0) as u64),
-                    Err(err) => {
-                        error!("Could not get download count from db: {err:#}");
-                        None
-                    }
-                })
-            }
-        })
-        .await
+        Memoized::builder(Duration::from_secs(60))
+            .hard_expiry(Duration::from_secs(10 * 60))
+            .refresh_every(Duration::from_secs(50), Duration::from_secs(10))
+            .build(move || {
+                let pg = Pool::clone(&pg);
+                async move {
+                    let row = sqlx::query(
+                        "SELECT COUNT(DISTINCT ip) FROM downloads WHERE file='BClickerDownloader'",
+                    )
+                    .fetch_one(&pg)
+                    .await
+                    .context("Could not get download count from db")?;
+                    Ok(Self(row.get::<i64, _>(0) as u64))
+                }
+            })
+            .await
     }
 }
 
@@ -106,14 +216,11 @@ impl DownloadCount {
 pub async fn get_download_count(
     download_counter: web::Data<Memoized<DownloadCount>>,
 ) -> actix_web::Result<impl Responder> {
-    let resp = match download_counter.get().await.0 {
+    let resp = match download_counter.get().await {
         None => HttpResponse::InternalServerError().finish(),
-        Some(count) => HttpResponse::Ok().body(count.to_string()),
+        Some(count) => HttpResponse::Ok()
+            .insert_header(count.age_header())
+            .body(count.value.0.to_string()),
     };
     Ok(resp)
 }
-
-#[get("/version")]
-pub async fn version(version: web::Data<Version>) -> impl Responder {
-    version.0.to_string()
-}
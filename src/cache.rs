@@ -1,70 +1,732 @@
+use actix_web::http::header::{HeaderName, AGE};
+use actix_web::rt::spawn;
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::time::{sleep, Instant};
+use futures::channel::oneshot;
+use futures::future::{select, Either};
 use futures::lock::Mutex;
+use log::{debug, error, warn};
+use std::collections::HashMap;
 use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
 use std::pin::Pin;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
+type Supplier<T> = Box<dyn Fn() -> Pin<Box<dyn Future<Output = anyhow::Result<T>>>> + Send + Sync>;
+
+const DEFAULT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Value recomputed by the supplier once it gets older than `timeout`.
+///
+/// Until `hard_expiry` the stale value is served right away while a single refresh runs in
+/// the background. Past `hard_expiry` callers wait for the refresh.
+///
+/// With [`MemoizedBuilder::refresh_every`] the value is also refreshed on a timer, so requests
+/// keep hitting a warm value even after an idle period.
+///
+/// A failed refresh keeps the last good value and the supplier is retried with exponential
+/// backoff, see [`MemoizedBuilder::retry_backoff`]. While backing off the last good value is
+/// served no matter how old it is.
 pub struct Memoized<T> {
-    supplier: Box<dyn Fn() -> Pin<Box<dyn Future<Output = T>>> + Send + Sync>,
+    inner: Arc<Inner<T>>,
+    refresher: std::sync::Mutex<Option<Refresher>>,
+}
+
+struct Refresher {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+struct Inner<T> {
+    supplier: Supplier<T>,
     timeout: Duration,
-    cached: Mutex<Cached<T>>,
+    hard_expiry: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    cached: std::sync::Mutex<Cached<T>>,
+    /// Held while the supplier runs, so there is only one refresh in flight.
+    refresh_lock: Mutex<()>,
+    background_refresh: AtomicBool,
 }
 
 pub struct Cached<T> {
-    at: Instant,
-    value: T,
+    /// Last good value and when it was supplied.
+    value: Option<(Instant, T)>,
+    failure: Option<Failure>,
+}
+
+struct Failure {
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+/// Value served by [`Memoized::get`].
+pub struct Served<T> {
+    pub value: T,
+    /// Time since the supplier returned the value.
+    pub age: Duration,
+}
+
+impl<T> Served<T> {
+    /// `Age` response header telling clients how stale the value is.
+    pub fn age_header(&self) -> (HeaderName, u64) {
+        (AGE, self.age.as_secs())
+    }
+}
+
+pub struct MemoizedBuilder<T> {
+    timeout: Duration,
+    hard_expiry: Option<Duration>,
+    refresh_every: Option<(Duration, Duration)>,
+    backoff: Option<(Duration, Duration)>,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> MemoizedBuilder<T>
+where
+    T: Clone + 'static,
+{
+    /// Age after which callers wait for the refresh instead of getting the stale value.
+    /// Defaults to the timeout, so stale values are never served.
+    pub fn hard_expiry(mut self, hard_expiry: Duration) -> Self {
+        self.hard_expiry = Some(hard_expiry);
+        self
+    }
+
+    /// Refreshes the value in a spawned task every `interval` plus a random delay of up to
+    /// `jitter`, so values sharing the interval don't all refresh at once.
+    pub fn refresh_every(mut self, interval: Duration, jitter: Duration) -> Self {
+        self.refresh_every = Some((interval, jitter));
+        self
+    }
+
+    /// Delay before retrying a failed supplier, doubled after every consecutive failure up to
+    /// `max`. Defaults to one second up to the timeout.
+    pub fn retry_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.backoff = Some((min, max));
+        self
+    }
+
+    /// Runs the supplier once before returning, so the value is warm unless that run fails.
+    pub async fn build<S, R>(self, supplier: S) -> Memoized<T>
+    where
+        S: (Fn() -> R) + Send + Sync + 'static,
+        R: Future<Output = anyhow::Result<T>> + 'static,
+    {
+        let (min_backoff, max_backoff) = self
+            .backoff
+            .unwrap_or((DEFAULT_MIN_BACKOFF, self.timeout.max(DEFAULT_MIN_BACKOFF)));
+        let inner = Arc::new(Inner {
+            supplier: Box::new(move || Box::pin(supplier())),
+            timeout: self.timeout,
+            hard_expiry: self.hard_expiry.unwrap_or(self.timeout).max(self.timeout),
+            min_backoff,
+            max_backoff: max_backoff.max(min_backoff),
+            cached: std::sync::Mutex::new(Cached {
+                value: None,
+                failure: None,
+            }),
+            refresh_lock: Mutex::new(()),
+            background_refresh: AtomicBool::new(false),
+        });
+        inner.refresh(Duration::ZERO).await;
+        let refresher = self
+            .refresh_every
+            .map(|(interval, jitter)| Refresher::spawn(Arc::downgrade(&inner), interval, jitter));
+        Memoized {
+            inner,
+            refresher: std::sync::Mutex::new(refresher),
+        }
+    }
+}
+
+impl Refresher {
+    fn spawn<T>(inner: Weak<Inner<T>>, interval: Duration, jitter: Duration) -> Self
+    where
+        T: Clone + 'static,
+    {
+        let (shutdown, mut shutdown_rx) = oneshot::channel();
+        let task = spawn(async move {
+            loop {
+                let jitter = Duration::from_millis(fastrand::u64(0..=jitter.as_millis() as u64));
+                let delay = Box::pin(sleep(interval + jitter));
+                // also resolves when the memoized value is dropped with the sender
+                if let Either::Right(_) = select(delay, &mut shutdown_rx).await {
+                    break;
+                }
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                inner.refresh(Duration::ZERO).await;
+            }
+            debug!("Memoized refresher stopped.");
+        });
+        Self { shutdown, task }
+    }
 }
 
 impl<T> Memoized<T>
 where
-    T: Clone,
+    T: Clone + 'static,
 {
+    /// Memoizes a supplier which can't fail, see [`Memoized::builder`] for the other options.
+    #[allow(dead_code)]
     pub async fn new<S, R>(timeout: Duration, supplier: S) -> Self
     where
         S: (Fn() -> R) + Send + Sync + 'static,
         R: Future<Output = T> + 'static,
     {
-        let cached = supplier().await;
-        Memoized {
-            supplier: Box::new(move || Box::pin(supplier())),
+        Self::builder(timeout)
+            .build(move || {
+                let value = supplier();
+                async move { anyhow::Ok(value.await) }
+            })
+            .await
+    }
+
+    pub fn builder(timeout: Duration) -> MemoizedBuilder<T> {
+        MemoizedBuilder {
             timeout,
-            cached: Mutex::new(Cached {
-                at: Instant::now(),
-                value: cached,
+            hard_expiry: None,
+            refresh_every: None,
+            backoff: None,
+            _value: PhantomData,
+        }
+    }
+
+    /// Stops the background refresher, waiting for a refresh in progress to finish.
+    pub async fn shutdown(&self) {
+        let refresher = self
+            .refresher
+            .lock()
+            .expect("Memoized refresher poisoned!")
+            .take();
+        if let Some(refresher) = refresher {
+            let _ = refresher.shutdown.send(());
+            let _ = refresher.task.await;
+        }
+    }
+
+    /// Returns `None` only if the supplier has never succeeded.
+    pub async fn get(&self) -> Option<Served<T>> {
+        {
+            let cached = self.inner.cached();
+            let served = cached.served();
+            match served {
+                Some(served) if served.age < self.inner.timeout => return Some(served),
+                served if cached.backing_off() => return served,
+                Some(served) if served.age < self.inner.hard_expiry => {
+                    drop(cached);
+                    self.refresh_in_background();
+                    return Some(served);
+                }
+                _ => {}
+            }
+        }
+        self.inner.refresh(self.inner.timeout).await
+    }
+
+    fn refresh_in_background(&self) {
+        if self.inner.background_refresh.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let inner = Arc::clone(&self.inner);
+        spawn(async move {
+            // also clears the flag if the supplier panics
+            let _flag = BackgroundRefresh(&inner.background_refresh);
+            inner.refresh(inner.timeout).await;
+        });
+    }
+}
+
+/// Clears [`Inner::background_refresh`] once the background refresh is done or unwound.
+struct BackgroundRefresh<'a>(&'a AtomicBool);
+
+impl Drop for BackgroundRefresh<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl<T> Cached<T>
+where
+    T: Clone,
+{
+    fn served(&self) -> Option<Served<T>> {
+        self.value.as_ref().map(|(at, value)| Served {
+            value: value.clone(),
+            age: at.elapsed(),
+        })
+    }
+
+    fn backing_off(&self) -> bool {
+        self.failure
+            .as_ref()
+            .is_some_and(|failure| failure.retry_at > Instant::now())
+    }
+}
+
+impl<T> Inner<T>
+where
+    T: Clone,
+{
+    fn cached(&self) -> std::sync::MutexGuard<'_, Cached<T>> {
+        self.cached.lock().expect("Memoized cache poisoned!")
+    }
+
+    /// Runs the supplier unless the value got refreshed within `max_age` or the last attempt
+    /// failed less than the backoff ago (e.g. by someone else while we were waiting for the
+    /// lock).
+    async fn refresh(&self, max_age: Duration) -> Option<Served<T>> {
+        let _refresh_guard = self.refresh_lock.lock().await;
+        {
+            let cached = self.cached();
+            let served = cached.served();
+            if cached.backing_off() || served.as_ref().is_some_and(|s| s.age < max_age) {
+                return served;
+            }
+        }
+        match (self.supplier)().await {
+            Ok(value) => {
+                *self.cached() = Cached {
+                    value: Some((Instant::now(), value.clone())),
+                    failure: None,
+                };
+                Some(Served {
+                    value,
+                    age: Duration::ZERO,
+                })
+            }
+            Err(err) => {
+                let mut cached = self.cached();
+                let backoff = cached.failure.as_ref().map_or(self.min_backoff, |failure| {
+                    (failure.backoff * 2).min(self.max_backoff)
+                });
+                cached.failure = Some(Failure {
+                    retry_at: Instant::now() + backoff,
+                    backoff,
+                });
+                let served = cached.served();
+                match &served {
+                    Some(served) => warn!(
+                        "{err:#}. Serving value from {}s ago, retrying in {backoff:?}.",
+                        served.age.as_secs()
+                    ),
+                    None => error!("{err:#}. No value to serve, retrying in {backoff:?}."),
+                }
+                served
+            }
+        }
+    }
+}
+
+type KeyedSupplier<K, V> =
+    Box<dyn Fn(K) -> Pin<Box<dyn Future<Output = anyhow::Result<V>>>> + Send + Sync>;
+
+/// Values recomputed per key by the supplier, for responses depending on query parameters.
+///
+/// Each key expires on its own, after the TTL given for it by [`MemoizedMapBuilder::ttl_by`].
+/// At most `max_entries` keys are kept, the least recently used one is evicted first. As with
+/// [`Memoized`] there is a single refresh in flight per key and a failed refresh keeps the last
+/// good value.
+pub struct MemoizedMap<K, V> {
+    supplier: KeyedSupplier<K, V>,
+    ttl: Box<dyn Fn(&K) -> Duration + Send + Sync>,
+    max_entries: usize,
+    entries: std::sync::Mutex<Entries<K, V>>,
+}
+
+struct Entries<K, V> {
+    map: HashMap<K, (u64, Arc<Entry<V>>)>,
+    /// Bumped on every access, the entry with the lowest stamp was used least recently.
+    clock: u64,
+}
+
+struct Entry<V> {
+    value: std::sync::Mutex<Option<(Instant, V)>>,
+    refresh_lock: Mutex<()>,
+}
+
+pub struct MemoizedMapBuilder<K, V> {
+    ttl: Box<dyn Fn(&K) -> Duration + Send + Sync>,
+    max_entries: usize,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<K, V> MemoizedMapBuilder<K, V>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    /// Replaces the shared TTL with one chosen per key, e.g. longer for ranges that can't
+    /// change anymore.
+    pub fn ttl_by<F>(mut self, ttl: F) -> Self
+    where
+        F: Fn(&K) -> Duration + Send + Sync + 'static,
+    {
+        self.ttl = Box::new(ttl);
+        self
+    }
+
+    pub fn build<S, R>(self, supplier: S) -> MemoizedMap<K, V>
+    where
+        S: (Fn(K) -> R) + Send + Sync + 'static,
+        R: Future<Output = anyhow::Result<V>> + 'static,
+    {
+        MemoizedMap {
+            supplier: Box::new(move |key| Box::pin(supplier(key))),
+            ttl: self.ttl,
+            max_entries: self.max_entries.max(1),
+            entries: std::sync::Mutex::new(Entries {
+                map: HashMap::new(),
+                clock: 0,
             }),
         }
     }
+}
 
-    pub async fn get(&self) -> T {
-        let mut cached = self.cached.lock().await;
-        if cached.at.elapsed() < self.timeout {
-            return cached.value.clone();
+impl<K, V> MemoizedMap<K, V>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    pub fn builder(ttl: Duration, max_entries: usize) -> MemoizedMapBuilder<K, V> {
+        MemoizedMapBuilder {
+            ttl: Box::new(move |_| ttl),
+            max_entries,
+            _value: PhantomData,
+        }
+    }
+
+    /// Fails only if the supplier fails and there is no previous value for the key.
+    pub async fn get(&self, key: K) -> anyhow::Result<Served<V>> {
+        let ttl = (self.ttl)(&key);
+        let entry = self.entry(&key);
+        if let Some(served) = entry.served().filter(|served| served.age < ttl) {
+            return Ok(served);
+        }
+        let _refresh_guard = entry.refresh_lock.lock().await;
+        // someone else might have refreshed it while we were waiting for the lock
+        if let Some(served) = entry.served().filter(|served| served.age < ttl) {
+            return Ok(served);
+        }
+        match (self.supplier)(key.clone()).await {
+            Ok(value) => {
+                *entry.value() = Some((Instant::now(), value.clone()));
+                Ok(Served {
+                    value,
+                    age: Duration::ZERO,
+                })
+            }
+            Err(err) => match entry.served() {
+                Some(served) => {
+                    warn!("{err:#}. Serving value from {}s ago.", served.age.as_secs());
+                    Ok(served)
+                }
+                None => {
+                    self.remove(&key, &entry);
+                    Err(err)
+                }
+            },
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries<K, V>> {
+        self.entries.lock().expect("Memoized map poisoned!")
+    }
+
+    fn entry(&self, key: &K) -> Arc<Entry<V>> {
+        let mut entries = self.entries();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some((used, entry)) = entries.map.get_mut(key) {
+            *used = clock;
+            return Arc::clone(entry);
+        }
+        if entries.map.len() >= self.max_entries {
+            let least_recently_used = entries
+                .map
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = least_recently_used {
+                entries.map.remove(&key);
+            }
         }
-        let new_value = (self.supplier)().await;
-        *cached = Cached {
-            at: Instant::now(),
-            value: new_value,
-        };
-        cached.value.clone()
+        let entry = Arc::new(Entry {
+            value: std::sync::Mutex::new(None),
+            refresh_lock: Mutex::new(()),
+        });
+        entries.map.insert(key.clone(), (clock, Arc::clone(&entry)));
+        entry
+    }
+
+    /// Drops an entry that never got a value, unless it has been replaced in the meantime.
+    fn remove(&self, key: &K, entry: &Arc<Entry<V>>) {
+        let mut entries = self.entries();
+        if entries
+            .map
+            .get(key)
+            .is_some_and(|(_, current)| Arc::ptr_eq(current, entry))
+        {
+            entries.map.remove(key);
+        }
+    }
+}
+
+impl<V> Entry<V>
+where
+    V: Clone,
+{
+    fn value(&self) -> std::sync::MutexGuard<'_, Option<(Instant, V)>> {
+        self.value.lock().expect("Memoized map entry poisoned!")
+    }
+
+    fn served(&self) -> Option<Served<V>> {
+        self.value().as_ref().map(|(at, value)| Served {
+            value: value.clone(),
+            age: at.elapsed(),
+        })
     }
 }
 
+#[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
     use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
     use std::sync::Arc;
-    use std::sync::atomic::{AtomicI32, Ordering};
+    use tokio::time;
 
     #[actix_web::test]
     pub async fn test() {
         let counter = Arc::new(AtomicI32::new(1));
         let memoized = Memoized::new(Duration::from_secs(5), move || {
             let counter = counter.clone();
+            async move { counter.fetch_add(1, Ordering::Relaxed) + 1 }
+        })
+        .await;
+        assert_eq!(memoized.get().await.unwrap().value, 2);
+        assert_eq!(memoized.get().await.unwrap().value, 2);
+    }
+
+    #[actix_web::test]
+    pub async fn test_stale_while_revalidate() {
+        time::pause();
+        let counter = Arc::new(AtomicI32::new(0));
+        let supplier_counter = Arc::clone(&counter);
+        let memoized = Memoized::builder(Duration::from_millis(20))
+            .hard_expiry(Duration::from_secs(5))
+            .build(move || {
+                let counter = supplier_counter.clone();
+                async move {
+                    sleep(Duration::from_millis(50)).await;
+                    anyhow::Ok(counter.fetch_add(1, Ordering::Relaxed) + 1)
+                }
+            })
+            .await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        time::advance(Duration::from_millis(30)).await;
+
+        let started_at = Instant::now();
+        let values = futures::future::join_all((0..10).map(|_| memoized.get())).await;
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+        assert!(values
+            .iter()
+            .all(|served| served.as_ref().is_some_and(|served| served.value == 1)));
+
+        sleep(Duration::from_millis(80)).await;
+        assert_eq!(memoized.get().await.unwrap().value, 2);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    #[actix_web::test]
+    pub async fn test_background_refresh_panic() {
+        time::pause();
+        let counter = Arc::new(AtomicI32::new(0));
+        let supplier_counter = Arc::clone(&counter);
+        let memoized = Memoized::builder(Duration::from_millis(20))
+            .hard_expiry(Duration::from_secs(5))
+            .build(move || {
+                let calls = supplier_counter.fetch_add(1, Ordering::Relaxed) + 1;
+                async move {
+                    if calls == 2 {
+                        panic!("supplier panicked");
+                    }
+                    anyhow::Ok(calls)
+                }
+            })
+            .await;
+        time::advance(Duration::from_millis(30)).await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+
+        // the panicked refresh doesn't block the next one
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(memoized.get().await.unwrap().value, 3);
+    }
+
+    #[actix_web::test]
+    pub async fn test_hard_expiry_single_flight() {
+        time::pause();
+        let counter = Arc::new(AtomicI32::new(0));
+        let supplier_counter = Arc::clone(&counter);
+        let memoized = Memoized::new(Duration::from_millis(20), move || {
+            let counter = supplier_counter.clone();
             async move {
+                sleep(Duration::from_millis(20)).await;
                 counter.fetch_add(1, Ordering::Relaxed) + 1
             }
         })
         .await;
-        assert_eq!(memoized.get().await, 2);
-        assert_eq!(memoized.get().await, 2);
+        time::advance(Duration::from_millis(30)).await;
+
+        let values = futures::future::join_all((0..10).map(|_| memoized.get())).await;
+        assert!(values
+            .iter()
+            .all(|served| served.as_ref().is_some_and(|served| served.value == 2)));
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    #[actix_web::test]
+    pub async fn test_refresh_every() {
+        time::pause();
+        let counter = Arc::new(AtomicI32::new(0));
+        let supplier_counter = Arc::clone(&counter);
+        let memoized = Memoized::builder(Duration::from_secs(60))
+            .refresh_every(Duration::from_millis(20), Duration::from_millis(5))
+            .build(move || {
+                let counter = supplier_counter.clone();
+                async move { anyhow::Ok(counter.fetch_add(1, Ordering::Relaxed) + 1) }
+            })
+            .await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        sleep(Duration::from_millis(100)).await;
+        assert!(memoized.get().await.unwrap().value >= 3);
+
+        memoized.shutdown().await;
+        let refreshed = counter.load(Ordering::Relaxed);
+        sleep(Duration::from_millis(60)).await;
+        assert_eq!(counter.load(Ordering::Relaxed), refreshed);
+    }
+
+    #[actix_web::test]
+    pub async fn test_failure_keeps_last_value() {
+        time::pause();
+        let calls = Arc::new(AtomicI32::new(0));
+        let failing = Arc::new(AtomicBool::new(false));
+        let (supplier_calls, supplier_failing) = (Arc::clone(&calls), Arc::clone(&failing));
+        let memoized = Memoized::builder(Duration::from_millis(20))
+            .retry_backoff(Duration::from_millis(50), Duration::from_millis(200))
+            .build(move || {
+                let calls = supplier_calls.fetch_add(1, Ordering::Relaxed) + 1;
+                let failing = supplier_failing.load(Ordering::Relaxed);
+                async move {
+                    if failing {
+                        anyhow::bail!("supplier failed");
+                    }
+                    Ok(calls)
+                }
+            })
+            .await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+
+        failing.store(true, Ordering::Relaxed);
+        time::advance(Duration::from_millis(30)).await;
+        let served = memoized.get().await.unwrap();
+        assert_eq!(served.value, 1);
+        assert!(served.age >= Duration::from_millis(30));
+        // backing off, the supplier isn't called again
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        time::advance(Duration::from_millis(60)).await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        // backoff doubled to 100ms
+        time::advance(Duration::from_millis(60)).await;
+        assert_eq!(memoized.get().await.unwrap().value, 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        failing.store(false, Ordering::Relaxed);
+        time::advance(Duration::from_millis(60)).await;
+        let served = memoized.get().await.unwrap();
+        assert_eq!(served.value, 4);
+        assert_eq!(served.age, Duration::ZERO);
+    }
+
+    #[actix_web::test]
+    pub async fn test_failure_without_value() {
+        time::pause();
+        let calls = Arc::new(AtomicI32::new(0));
+        let supplier_calls = Arc::clone(&calls);
+        let memoized: Memoized<i32> = Memoized::builder(Duration::from_secs(60))
+            .retry_backoff(Duration::from_millis(20), Duration::from_millis(20))
+            .build(move || {
+                supplier_calls.fetch_add(1, Ordering::Relaxed);
+                async { Err(anyhow::anyhow!("supplier failed")) }
+            })
+            .await;
+        assert!(memoized.get().await.is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        time::advance(Duration::from_millis(30)).await;
+        assert!(memoized.get().await.is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[actix_web::test]
+    pub async fn test_memoized_map() {
+        time::pause();
+        let calls = Arc::new(AtomicI32::new(0));
+        let supplier_calls = Arc::clone(&calls);
+        let memoized = MemoizedMap::builder(Duration::from_secs(60), 2)
+            .ttl_by(|key: &i32| {
+                if *key == 3 {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs(60)
+                }
+            })
+            .build(move |key: i32| {
+                supplier_calls.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    sleep(Duration::from_millis(20)).await;
+                    if key < 0 {
+                        anyhow::bail!("negative key");
+                    }
+                    Ok(key * 10)
+                }
+            });
+
+        let values = futures::future::join_all((0..10).map(|_| memoized.get(1))).await;
+        assert!(values
+            .iter()
+            .all(|served| served.as_ref().is_ok_and(|served| served.value == 10)));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        assert_eq!(memoized.get(2).await.unwrap().value, 20);
+        assert_eq!(memoized.get(1).await.unwrap().value, 10);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        // evicts 2, the least recently used one
+        assert_eq!(memoized.get(3).await.unwrap().value, 30);
+        assert_eq!(memoized.get(1).await.unwrap().value, 10);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert_eq!(memoized.get(2).await.unwrap().value, 20);
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // zero ttl
+        memoized.get(3).await.unwrap();
+        memoized.get(3).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+
+        // failures aren't cached
+        assert!(memoized.get(-1).await.is_err());
+        assert!(memoized.get(-1).await.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 8);
     }
 }
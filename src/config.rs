@@ -0,0 +1,574 @@
+use crate::yt::data_api;
+use anyhow::{bail, Context};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const MIN_ADMIN_TOKEN_LEN: usize = 32;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub file_host: FileHostConfig,
+    pub buzkaaclicker: BuzkaaClickerConfig,
+    pub admin: AdminConfig,
+    pub online_users: OnlineUsersConfig,
+    pub youtube: YoutubeConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Hosts serving the api used by the clicker and the website.
+    pub api_hosts: Vec<String>,
+    /// Hosts serving the website and the public downloads.
+    pub site_hosts: Vec<String>,
+    pub static_dir: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileHostConfig {
+    /// Manifest listing the hosted files, see `filehost/manifest.example.toml`.
+    pub manifest: PathBuf,
+    /// How often the manifest is checked for changes.
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuzkaaClickerConfig {
+    /// Version reported by `/version` to clients without a platform while the release catalog is
+    /// empty.
+    pub version: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnlineUsersConfig {
+    /// How long the per minute samples are kept. Older ones only live on in the hourly and daily
+    /// rollups.
+    pub minute_retention_days: u32,
+    /// How often the rollups are updated.
+    pub rollup_interval_secs: u64,
+    /// Minimum time between two online count events pushed over `/events`.
+    pub push_interval_secs: u64,
+    /// How often the presence is saved, on top of saving it on shutdown.
+    pub presence_save_interval_secs: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct YoutubeConfig {
+    /// Channels served at `/youtube/{slug}`.
+    pub channels: Vec<ChannelConfig>,
+    /// Live status providers, tried in order until one of them answers.
+    pub providers: Vec<LiveProviderKind>,
+    /// Required by the `data_api` provider.
+    pub data_api_key: Option<String>,
+    /// Minimum time between two data api visits of a channel, so all channels fit in the daily
+    /// quota.
+    pub data_api_interval_secs: u64,
+    pub scraper_base_url: String,
+    pub data_api_base_url: String,
+    /// Notified when a channel goes live or offline.
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Recorded with the delivery attempts instead of the url, which usually holds a secret.
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// Message with an embed, for discord (and compatible) webhooks.
+    #[default]
+    Discord,
+    /// Plain json describing the event.
+    Generic,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveProviderKind {
+    /// Scrapes the channel pages, breaks whenever youtube changes its markup.
+    Scraper,
+    /// Official YouTube Data API, limited by the daily quota.
+    DataApi,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// Youtube channel id.
+    pub id: String,
+    /// Display name.
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer tokens accepted by the `/admin` api. The api rejects everything when empty.
+    pub tokens: Vec<AdminToken>,
+    /// Uploads larger than this are rejected while they are streamed.
+    pub max_upload_mb: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    /// Recorded in the audit log.
+    pub name: String,
+    pub token: String,
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminToken")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for YoutubeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("YoutubeConfig")
+            .field("channels", &self.channels)
+            .field("providers", &self.providers)
+            .field("data_api_interval_secs", &self.data_api_interval_secs)
+            .field("scraper_base_url", &self.scraper_base_url)
+            .field("data_api_base_url", &self.data_api_base_url)
+            .field("webhooks", &self.webhooks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("name", &self.name)
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 2137)),
+            api_hosts: vec![
+                String::from("apiv2.makin.cc"),
+                String::from("buzkaaclickerapi.firma.sex.pl"),
+            ],
+            site_hosts: vec![
+                String::from("buzkaaclicker.pl"),
+                String::from("buzkaaclicker.firma.sex.pl"),
+            ],
+            static_dir: PathBuf::from("./static"),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("db.sqlite"),
+        }
+    }
+}
+
+impl Default for FileHostConfig {
+    fn default() -> Self {
+        Self {
+            manifest: PathBuf::from("./filehost/manifest.toml"),
+            reload_interval_secs: 10,
+        }
+    }
+}
+
+impl Default for OnlineUsersConfig {
+    fn default() -> Self {
+        Self {
+            minute_retention_days: 30,
+            rollup_interval_secs: 60 * 60,
+            push_interval_secs: 2,
+            presence_save_interval_secs: 15,
+        }
+    }
+}
+
+impl Default for YoutubeConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![ChannelConfig {
+                id: String::from("UCL1s7OtDPaX3SdhW5433PRw"),
+                name: String::from("Buzkaa"),
+                slug: String::from("Buzkaa"),
+            }],
+            providers: vec![LiveProviderKind::Scraper],
+            data_api_key: None,
+            data_api_interval_secs: 15 * 60,
+            scraper_base_url: String::from("https://www.youtube.com"),
+            data_api_base_url: String::from("https://www.googleapis.com/youtube/v3"),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            max_upload_mb: 512,
+        }
+    }
+}
+
+impl Config {
+    /// Loads config from `BCLICKER_CONFIG` (or `config.toml` if present), applies env overrides
+    /// and validates the result.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut config = match env::var("BCLICKER_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => {
+                info!("No {DEFAULT_CONFIG_PATH} found, using default config.");
+                Self::default()
+            }
+        };
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        info!("Loading config from {}.", path.display());
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        toml::from_str(&raw).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        if let Some(bind) = env_override("BCLICKER_BIND")? {
+            self.server.bind = bind;
+        }
+        if let Some(hosts) = env_list("BCLICKER_API_HOSTS") {
+            self.server.api_hosts = hosts;
+        }
+        if let Some(hosts) = env_list("BCLICKER_SITE_HOSTS") {
+            self.server.site_hosts = hosts;
+        }
+        if let Ok(path) = env::var("BCLICKER_DB_PATH") {
+            self.database.path = PathBuf::from(path);
+        }
+        if let Some(version) = env_override("BUZKAACLICKER_VERSION")? {
+            self.buzkaaclicker.version = version;
+        }
+        if let Ok(key) = env::var("BCLICKER_YOUTUBE_API_KEY") {
+            self.youtube.data_api_key = Some(key);
+        }
+        if let Some(tokens) = env_list("BCLICKER_ADMIN_TOKENS") {
+            self.admin.tokens = tokens
+                .iter()
+                .map(|token| {
+                    let (name, token) = token
+                        .split_once(':')
+                        .context("BCLICKER_ADMIN_TOKENS entries must be in name:token format!")?;
+                    Ok(AdminToken {
+                        name: name.to_owned(),
+                        token: token.to_owned(),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.server.api_hosts.is_empty() {
+            bail!("server.api_hosts must not be empty");
+        }
+        if self.server.site_hosts.is_empty() {
+            bail!("server.site_hosts must not be empty");
+        }
+        if self.buzkaaclicker.version == 0 {
+            bail!("buzkaaclicker.version (or BUZKAACLICKER_VERSION env variable) must be set");
+        }
+        if self.file_host.reload_interval_secs == 0 {
+            bail!("file_host.reload_interval_secs must be greater than 0");
+        }
+        // the default chart shows the minute samples of the last week
+        if self.online_users.minute_retention_days < 7 {
+            bail!("online_users.minute_retention_days must be at least 7");
+        }
+        if self.online_users.rollup_interval_secs == 0 {
+            bail!("online_users.rollup_interval_secs must be greater than 0");
+        }
+        if self.online_users.push_interval_secs == 0 {
+            bail!("online_users.push_interval_secs must be greater than 0");
+        }
+        if self.online_users.presence_save_interval_secs == 0 {
+            bail!("online_users.presence_save_interval_secs must be greater than 0");
+        }
+        if self.youtube.providers.is_empty() {
+            bail!("youtube.providers must not be empty");
+        }
+        if self.youtube.providers.len() != HashSet::<_>::from_iter(&self.youtube.providers).len() {
+            bail!("youtube.providers must not repeat a provider");
+        }
+        if self.youtube.providers.contains(&LiveProviderKind::DataApi)
+            && self.youtube.data_api_key.is_none()
+        {
+            bail!("youtube.data_api_key (or BCLICKER_YOUTUBE_API_KEY env variable) must be set to use the data_api provider");
+        }
+        if self.youtube.providers.contains(&LiveProviderKind::DataApi) {
+            if self.youtube.data_api_interval_secs < 15 * 60 {
+                bail!("youtube.data_api_interval_secs must be at least 900");
+            }
+            let cost = data_api::daily_cost(
+                self.youtube.channels.len(),
+                Duration::from_secs(self.youtube.data_api_interval_secs),
+            );
+            if cost > data_api::DAILY_QUOTA {
+                bail!(
+                    "youtube.data_api_interval_secs is too short for {} channels, they would use {cost} of the {} daily data api quota units",
+                    self.youtube.channels.len(),
+                    data_api::DAILY_QUOTA
+                );
+            }
+        }
+        let mut slugs = HashSet::new();
+        for channel in &self.youtube.channels {
+            if channel.slug.is_empty()
+                || !channel
+                    .slug
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("youtube channel slug '{}' is invalid", channel.slug);
+            }
+            // taken by the endpoints listing live channels and past streams
+            if ["live", "streams"].contains(&channel.slug.as_str()) {
+                bail!("youtube channel slug '{}' is reserved", channel.slug);
+            }
+            if !slugs.insert(&channel.slug) {
+                bail!(
+                    "youtube channel slug '{}' is used more than once",
+                    channel.slug
+                );
+            }
+        }
+        let mut webhook_names = HashSet::new();
+        for webhook in &self.youtube.webhooks {
+            if !webhook_names.insert(&webhook.name) {
+                bail!("webhook name '{}' is used more than once", webhook.name);
+            }
+            if !webhook.url.starts_with("https://") && !webhook.url.starts_with("http://") {
+                bail!("webhook '{}' url must be a http(s) url", webhook.name);
+            }
+        }
+        let mut admin_names = HashSet::new();
+        for token in &self.admin.tokens {
+            if !admin_names.insert(&token.name) {
+                bail!("admin token name '{}' is used more than once", token.name);
+            }
+            if token.token.len() < MIN_ADMIN_TOKEN_LEN {
+                bail!(
+                    "admin token '{}' must be at least {MIN_ADMIN_TOKEN_LEN} characters long",
+                    token.name
+                );
+            }
+        }
+        if self.admin.max_upload_mb == 0 {
+            bail!("admin.max_upload_mb must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+fn env_override<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid {name} env variable!")),
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_partial_file_uses_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            bind = "127.0.0.1:8080"
+
+            [buzkaaclicker]
+            version = 17
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.bind, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(config.server.api_hosts, ServerConfig::default().api_hosts);
+        assert_eq!(config.database.path, PathBuf::from("db.sqlite"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    pub fn test_example_config() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    pub fn test_validate_admin_tokens() {
+        let mut config = Config::default();
+        config.buzkaaclicker.version = 16;
+        let token = AdminToken {
+            name: String::from("makin"),
+            token: "x".repeat(MIN_ADMIN_TOKEN_LEN),
+        };
+        config.admin.tokens = vec![token.clone()];
+        assert!(config.validate().is_ok());
+        config.admin.tokens = vec![token.clone(), token.clone()];
+        assert!(config.validate().is_err());
+        config.admin.tokens = vec![AdminToken {
+            token: String::from("short"),
+            ..token
+        }];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    pub fn test_validate_channels() {
+        let mut config = Config::default();
+        config.buzkaaclicker.version = 16;
+        let channel = config.youtube.channels[0].clone();
+        config.youtube.channels.push(ChannelConfig {
+            slug: String::from("Partner"),
+            ..channel.clone()
+        });
+        assert!(config.validate().is_ok());
+        config.youtube.channels.push(channel.clone());
+        assert!(config.validate().is_err());
+        config.youtube.channels = vec![ChannelConfig {
+            slug: String::from("live"),
+            ..channel.clone()
+        }];
+        assert!(config.validate().is_err());
+        config.youtube.channels = vec![ChannelConfig {
+            slug: String::from("../Buzkaa"),
+            ..channel
+        }];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    pub fn test_validate_providers() {
+        let mut config = Config::default();
+        config.buzkaaclicker.version = 16;
+        config.youtube.providers = vec![LiveProviderKind::DataApi, LiveProviderKind::Scraper];
+        assert!(config.validate().is_err());
+        config.youtube.data_api_key = Some(String::from("key"));
+        assert!(config.validate().is_ok());
+        config.youtube.data_api_interval_secs = 60;
+        assert!(config.validate().is_err());
+        config.youtube.data_api_interval_secs = 15 * 60;
+        config.youtube.channels.push(ChannelConfig {
+            id: String::from("UCxxxxxxxxxxxxxxxxxxxxxx"),
+            name: String::from("Other"),
+            slug: String::from("Other"),
+        });
+        assert!(config.validate().is_err());
+        config.youtube.data_api_interval_secs = 30 * 60;
+        assert!(config.validate().is_ok());
+        config.youtube.providers = vec![LiveProviderKind::Scraper, LiveProviderKind::Scraper];
+        assert!(config.validate().is_err());
+        config.youtube.providers = Vec::new();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    pub fn test_validate_webhooks() {
+        let mut config = Config::default();
+        config.buzkaaclicker.version = 16;
+        let webhook = WebhookConfig {
+            name: String::from("discord"),
+            url: String::from("https://discord.com/api/webhooks/1/token"),
+            format: WebhookFormat::Discord,
+        };
+        config.youtube.webhooks = vec![webhook.clone()];
+        assert!(config.validate().is_ok());
+        config.youtube.webhooks = vec![webhook.clone(), webhook.clone()];
+        assert!(config.validate().is_err());
+        config.youtube.webhooks = vec![WebhookConfig {
+            url: String::from("discord.com/api/webhooks/1/token"),
+            ..webhook
+        }];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    pub fn test_validate_version() {
+        let mut config = Config::default();
+        assert!(config.validate().is_err());
+        config.buzkaaclicker.version = 16;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    pub fn test_validate_minute_retention() {
+        let mut config = Config::default();
+        config.buzkaaclicker.version = 16;
+        config.online_users.minute_retention_days = 7;
+        assert!(config.validate().is_ok());
+        config.online_users.minute_retention_days = 6;
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{bail, Context};
+use chrono::{Days, NaiveDate, NaiveDateTime, Utc};
+use std::time::Duration;
+
+/// Responses kept by the endpoints caching one response per query.
+pub const MAX_CACHED_QUERIES: usize = 64;
+
+/// Whole UTC days from `from` to `to` (both inclusive), as accepted by the stats endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Start of the day after `to`.
+    end: NaiveDateTime,
+}
+
+impl DateRange {
+    /// `to` defaults to today and `from` to `default_days` days up to `to`. Ranges longer than
+    /// `max_days` days and dates at the edge of the calendar are rejected.
+    pub fn resolve(
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        default_days: u64,
+        max_days: u64,
+    ) -> anyhow::Result<Self> {
+        let to = to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = match from {
+            Some(from) => from,
+            None => to
+                .checked_sub_days(Days::new(default_days.saturating_sub(1)))
+                .context("to is out of range")?,
+        };
+        if from > to {
+            bail!("from must not be after to");
+        }
+        let range = Self {
+            from,
+            to,
+            end: to
+                .checked_add_days(Days::new(1))
+                .context("to is out of range")?
+                .and_time(Default::default()),
+        };
+        if range.days() > max_days {
+            bail!("range must not be longer than {max_days} days");
+        }
+        Ok(range)
+    }
+
+    pub fn days(&self) -> u64 {
+        (self.to - self.from).num_days() as u64 + 1
+    }
+
+    /// Inclusive lower bound in the format timestamps are stored in.
+    pub fn start_time(&self) -> String {
+        self.from.and_time(Default::default()).to_string()
+    }
+
+    /// Exclusive upper bound in the format timestamps are stored in.
+    pub fn end_time(&self) -> String {
+        self.end.to_string()
+    }
+}
+
+/// How long a response for the range ending on `to` (today when `None`) is cached. Ranges that
+/// ended before today can't change anymore, so they are kept for longer.
+pub fn cache_ttl(to: Option<NaiveDate>) -> Duration {
+    match to {
+        Some(to) if to < Utc::now().date_naive() => Duration::from_secs(60 * 60),
+        _ => Duration::from_secs(60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_resolve() {
+        let day = |s: &str| Some(s.parse::<NaiveDate>().unwrap());
+        let range = DateRange::resolve(None, day("2026-10-17"), 7, 30).unwrap();
+        assert_eq!(range.from, day("2026-10-11").unwrap());
+        assert_eq!(range.days(), 7);
+        assert_eq!(range.start_time(), "2026-10-11 00:00:00");
+        assert_eq!(range.end_time(), "2026-10-18 00:00:00");
+        assert_eq!(DateRange::resolve(None, None, 1, 1).unwrap().days(), 1);
+
+        assert!(DateRange::resolve(day("2026-10-18"), day("2026-10-17"), 7, 30).is_err());
+        assert!(DateRange::resolve(day("2026-09-17"), day("2026-10-17"), 7, 30).is_err());
+        assert!(DateRange::resolve(day("2026-09-18"), day("2026-10-17"), 7, 30).is_ok());
+
+        assert!(DateRange::resolve(None, Some(NaiveDate::MAX), 7, 30).is_err());
+        assert!(DateRange::resolve(None, Some(NaiveDate::MIN), 7, 30).is_err());
+        assert!(DateRange::resolve(Some(NaiveDate::MIN), Some(NaiveDate::MAX), 7, 30).is_err());
+    }
+}
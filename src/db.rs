@@ -0,0 +1,14 @@
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Pool, Sqlite};
+
+/// Fresh in-memory database with the migrations applied. Limited to one connection, every
+/// connection to `sqlite::memory:` opens its own empty database.
+pub async fn test_pool() -> Pool<Sqlite> {
+    let pg = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pg).await.unwrap();
+    pg
+}
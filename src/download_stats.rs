@@ -0,0 +1,307 @@
+use crate::cache::{Memoized, MemoizedMap};
+use crate::date_range::{self, DateRange, MAX_CACHED_QUERIES};
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::NaiveDate;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::time::Duration;
+
+const DEFAULT_RANGE_DAYS: u64 = 30;
+const MAX_RANGE_DAYS: u64 = 3 * 366;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Day,
+    Week,
+}
+
+impl Interval {
+    /// SQLite expression truncating `time` to the start of the period.
+    fn period_sql(self) -> &'static str {
+        match self {
+            Interval::Day => "date(time)",
+            // weeks start on monday
+            Interval::Week => "date(time, 'weekday 0', '-6 days')",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StatsQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub interval: Interval,
+}
+
+impl StatsQuery {
+    pub fn range(&self) -> anyhow::Result<DateRange> {
+        DateRange::resolve(self.from, self.to, DEFAULT_RANGE_DAYS, MAX_RANGE_DAYS)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadStats {
+    from: NaiveDate,
+    /// Inclusive.
+    to: NaiveDate,
+    interval: Interval,
+    files: Vec<FileTotals>,
+    series: Vec<SeriesPoint>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileTotals {
+    file: String,
+    downloads: u64,
+    unique_downloaders: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SeriesPoint {
+    file: String,
+    /// First day of the period.
+    period: NaiveDate,
+    downloads: u64,
+    unique_downloaders: u64,
+}
+
+pub async fn get_download_stats(
+    pg: &Pool<Sqlite>,
+    query: StatsQuery,
+) -> anyhow::Result<DownloadStats> {
+    let range = query.range()?;
+    let start_time = range.start_time();
+    let end_time = range.end_time();
+
+    let rows = sqlx::query(
+        "select file, count(*) as downloads, count(distinct ip) as unique_downloaders \
+         from downloads where time >= ? and time < ? \
+         group by file order by downloads desc;",
+    )
+    .bind(&start_time)
+    .bind(&end_time)
+    .fetch_all(pg)
+    .await
+    .context("Could not select download totals")?;
+    let files = rows
+        .into_iter()
+        .map(|row| FileTotals {
+            file: row.get("file"),
+            downloads: row.get::<i64, _>("downloads") as u64,
+            unique_downloaders: row.get::<i64, _>("unique_downloaders") as u64,
+        })
+        .collect();
+
+    let rows = sqlx::query(&format!(
+        "select file, {} as period, count(*) as downloads, \
+             count(distinct ip) as unique_downloaders \
+         from downloads where time >= ? and time < ? \
+         group by file, period order by period, file;",
+        query.interval.period_sql()
+    ))
+    .bind(&start_time)
+    .bind(&end_time)
+    .fetch_all(pg)
+    .await
+    .context("Could not select download series")?;
+    let series = rows
+        .into_iter()
+        .map(|row| {
+            Ok(SeriesPoint {
+                file: row.get("file"),
+                period: row
+                    .get::<String, _>("period")
+                    .parse()
+                    .context("Invalid period")?,
+                downloads: row.get::<i64, _>("downloads") as u64,
+                unique_downloaders: row.get::<i64, _>("unique_downloaders") as u64,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(DownloadStats {
+        from: range.from,
+        to: range.to,
+        interval: query.interval,
+        files,
+        series,
+    })
+}
+
+/// Stats for the default query (last 30 days, daily), which is what the site draws.
+#[derive(Clone)]
+pub struct DownloadStatsJson(String);
+
+impl DownloadStatsJson {
+    pub async fn memoized(pg: Pool<Sqlite>) -> Memoized<Self> {
+        Memoized::builder(Duration::from_secs(60))
+            .hard_expiry(Duration::from_secs(10 * 60))
+            .refresh_every(Duration::from_secs(50), Duration::from_secs(10))
+            .build(move || {
+                let pg = Pool::clone(&pg);
+                async move {
+                    debug!("Generating new download stats json...");
+                    let stats = get_download_stats(&pg, default_query())
+                        .await
+                        .context("Could not get download stats")?;
+                    let json = serde_json::to_string(&stats)
+                        .context("Could not serialize download stats")?;
+                    Ok(Self(json))
+                }
+            })
+            .await
+    }
+
+    /// Stats for the other queries.
+    pub fn memoized_by_query(pg: Pool<Sqlite>) -> MemoizedMap<StatsQuery, Self> {
+        MemoizedMap::builder(Duration::from_secs(60), MAX_CACHED_QUERIES)
+            .ttl_by(|query: &StatsQuery| date_range::cache_ttl(query.to))
+            .build(move |query| {
+                let pg = Pool::clone(&pg);
+                async move {
+                    debug!("Generating new download stats json for {query:?}...");
+                    let stats = get_download_stats(&pg, query).await?;
+                    let json = serde_json::to_string(&stats)
+                        .context("Could not serialize download stats")?;
+                    Ok(Self(json))
+                }
+            })
+    }
+}
+
+fn default_query() -> StatsQuery {
+    StatsQuery {
+        from: None,
+        to: None,
+        interval: Interval::Day,
+    }
+}
+
+#[get("/download-stats")]
+pub async fn get_stats(
+    query: web::Query<StatsQuery>,
+    stats: web::Data<Memoized<DownloadStatsJson>>,
+    stats_by_query: web::Data<MemoizedMap<StatsQuery, DownloadStatsJson>>,
+) -> actix_web::Result<impl Responder> {
+    let stats = if *query == default_query() {
+        stats
+            .get()
+            .await
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("no data"))?
+    } else {
+        query
+            .range()
+            .map_err(|err| actix_web::error::ErrorBadRequest(format!("{err:#}")))?;
+        stats_by_query.get(*query).await.map_err(|err| {
+            error!("Could not get download stats for {query:?}: {err:#}");
+            actix_web::error::ErrorInternalServerError("could not get download stats")
+        })?
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(stats.age_header())
+        .body(stats.value.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    pub async fn test_stats() {
+        let pg = db::test_pool().await;
+        for (time, ip, file) in [
+            ("2026-10-05 10:00:00", "1.1.1.1", "BClickerDownloader"),
+            ("2026-10-05 11:00:00", "1.1.1.1", "BClickerDownloader"),
+            ("2026-10-06 10:00:00", "2.2.2.2", "BClickerDownloader"),
+            ("2026-10-12 10:00:00", "2.2.2.2", "BuzkaaClicker"),
+            ("2026-10-20 10:00:00", "3.3.3.3", "BuzkaaClicker"),
+        ] {
+            sqlx::query("insert into downloads (time, ip, file) values (?, ?, ?);")
+                .bind(time)
+                .bind(ip)
+                .bind(file)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+        let query = StatsQuery {
+            from: Some(NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()),
+            to: Some(NaiveDate::from_ymd_opt(2026, 10, 12).unwrap()),
+            interval: Interval::Week,
+        };
+        let stats = get_download_stats(&pg, query).await.unwrap();
+        assert_eq!(stats.files.len(), 2);
+        assert_eq!(stats.files[0].file, "BClickerDownloader");
+        assert_eq!(stats.files[0].downloads, 3);
+        assert_eq!(stats.files[0].unique_downloaders, 2);
+        assert_eq!(stats.series.len(), 2);
+        assert_eq!(
+            stats.series[0].period,
+            NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()
+        );
+        assert_eq!(
+            stats.series[1].period,
+            NaiveDate::from_ymd_opt(2026, 10, 12).unwrap()
+        );
+
+        let query = StatsQuery {
+            interval: Interval::Day,
+            ..query
+        };
+        let stats = get_download_stats(&pg, query).await.unwrap();
+        assert_eq!(stats.series.len(), 3);
+        assert_eq!(stats.series[0].unique_downloaders, 1);
+        assert_eq!(stats.series[0].downloads, 2);
+    }
+
+    #[actix_web::test]
+    pub async fn test_get_stats_errors() {
+        let pg = db::test_pool().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    DownloadStatsJson::memoized(Pool::clone(&pg)).await,
+                ))
+                .app_data(web::Data::new(DownloadStatsJson::memoized_by_query(
+                    Pool::clone(&pg),
+                )))
+                .service(get_stats),
+        )
+        .await;
+        let status = |uri: &'static str| {
+            let app = &app;
+            async move {
+                test::call_service(app, TestRequest::get().uri(uri).to_request())
+                    .await
+                    .status()
+            }
+        };
+        assert_eq!(
+            status("/download-stats?from=2026-10-06&to=2026-10-05").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status("/download-stats?from=2026-10-05&to=2026-10-06").await,
+            StatusCode::OK
+        );
+        pg.close().await;
+        assert_eq!(
+            status("/download-stats?from=2026-10-04&to=2026-10-06").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::online_users::OnlineUsersData;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::rt::time;
+use actix_web::web::{Bytes, Data};
+use actix_web::{get, HttpResponse};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::debug;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Events a slow client can fall behind before it gets disconnected.
+const CLIENT_BUFFER: usize = 8;
+/// Comment sent when nothing happened for a while, so proxies keep the connection open and gone
+/// clients get noticed.
+const KEEP_ALIVE_AFTER: Duration = Duration::from_secs(30);
+
+/// Clients subscribed to the server-sent events stream.
+pub struct Events {
+    clients: Mutex<Vec<mpsc::Sender<Bytes>>>,
+}
+
+pub enum Event {
+    Online(u32),
+}
+
+impl Event {
+    fn to_sse(&self) -> Bytes {
+        match self {
+            Event::Online(count) => Bytes::from(format!("event: online\ndata: {count}\n\n")),
+        }
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn clients(&self) -> std::sync::MutexGuard<'_, Vec<mpsc::Sender<Bytes>>> {
+        self.clients.lock().expect("Events clients poisoned!")
+    }
+
+    /// Registers a new client, starting its stream with `first`.
+    pub fn subscribe(&self, first: &Event) -> mpsc::Receiver<Bytes> {
+        let (mut client, receiver) = mpsc::channel(CLIENT_BUFFER);
+        let _ = client.try_send(first.to_sse());
+        self.clients().push(client);
+        receiver
+    }
+
+    pub fn broadcast(&self, event: &Event) {
+        self.send(event.to_sse());
+    }
+
+    /// Drops the clients that disconnected or can't keep up.
+    fn send(&self, message: Bytes) {
+        let mut clients = self.clients();
+        clients.retain_mut(|client| client.try_send(message.clone()).is_ok());
+        debug!("Sent event to {} clients.", clients.len());
+    }
+}
+
+/// Pushes the online count whenever it changes, checking at most once per `every`.
+pub async fn start_online_events(
+    online_users: OnlineUsersData,
+    events: Data<Events>,
+    every: Duration,
+) {
+    let mut push_interval = time::interval(every);
+    let mut last_count = None;
+    let mut last_sent_at = Instant::now();
+    loop {
+        push_interval.tick().await;
+        let count = online_users.lock().expect("Online users poisoned!").count();
+        if last_count != Some(count) {
+            events.broadcast(&Event::Online(count));
+            last_count = Some(count);
+            last_sent_at = Instant::now();
+        } else if last_sent_at.elapsed() >= KEEP_ALIVE_AFTER {
+            events.send(Bytes::from_static(b": keep-alive\n\n"));
+            last_sent_at = Instant::now();
+        }
+    }
+}
+
+#[get("/events")]
+pub async fn stream(online_users: OnlineUsersData, events: Data<Events>) -> HttpResponse {
+    let count = online_users.lock().expect("Online users poisoned!").count();
+    let receiver = events.subscribe(&Event::Online(count));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // nginx would buffer the events otherwise
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(receiver.map(Ok::<_, Infallible>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::online_users::OnlineUsers;
+    use actix_web::rt::spawn;
+
+    #[actix_web::test]
+    pub async fn test_online_events() {
+        let online_users = Data::new(Mutex::new(OnlineUsers::new()));
+        let events = Data::new(Events::new());
+        let mut receiver = events.subscribe(&Event::Online(0));
+        let gone = events.subscribe(&Event::Online(0));
+        drop(gone);
+        spawn(start_online_events(
+            Data::clone(&online_users),
+            Data::clone(&events),
+            Duration::from_millis(20),
+        ));
+
+        assert_eq!(receiver.next().await.unwrap(), "event: online\ndata: 0\n\n");
+        // first tick
+        assert_eq!(receiver.next().await.unwrap(), "event: online\ndata: 0\n\n");
+        assert_eq!(events.clients().len(), 1);
+
+        for ip in ["1.1.1.1", "2.2.2.2"] {
+            online_users
+                .lock()
+                .unwrap()
+                .keep_alive(String::from(ip), None);
+        }
+        assert_eq!(receiver.next().await.unwrap(), "event: online\ndata: 2\n\n");
+        time::sleep(Duration::from_millis(60)).await;
+        assert!(receiver.try_recv().is_err());
+    }
+}
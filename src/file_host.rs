@@ -1,49 +1,320 @@
 use actix_files::NamedFile;
-use actix_web::{web, HttpRequest};
-use anyhow::Context;
-use log::error;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::mime::Mime;
+use actix_web::rt::{task, time};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use anyhow::{bail, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Sqlite};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 pub struct FileHost {
     pg: Pool<Sqlite>,
+    manifest_path: PathBuf,
+    table: RwLock<Arc<FileTable>>,
+    /// Serializes manifest edits made through the admin api.
+    manifest_lock: futures::lock::Mutex<()>,
+}
+
+/// Snapshot of the manifest. Swapped as a whole on reload, so downloads which already grabbed
+/// it keep serving from the old one.
+struct FileTable {
+    manifest_modified: Option<SystemTime>,
     default_file: String,
-    files: HashMap<String, PathBuf>,
+    files: HashMap<String, HostedFile>,
 }
 
-impl FileHost {
-    pub fn new(pg: Pool<Sqlite>, default_file: String, files: HashMap<String, PathBuf>) -> Self {
-        let files_lowercase = HashMap::from_iter(
-            files
-                .into_iter()
-                .map(|(name, path)| (name.to_lowercase(), path)),
-        );
+pub struct HostedFile {
+    pub name: String,
+    pub path: PathBuf,
+    pub content_type: Option<Mime>,
+    pub description: String,
+    /// `None` if the file does not exist.
+    stamp: Option<FileStamp>,
+    /// Computed on registration and kept until the file stamp changes.
+    integrity: Option<FileIntegrity>,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileListing {
+    pub name: String,
+    pub description: String,
+    pub content_type: Option<String>,
+    pub default: bool,
+    #[serde(flatten)]
+    pub integrity: Option<FileIntegrity>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FileIntegrity {
+    /// Lowercase hex encoded SHA-256 of the file contents.
+    pub sha256: String,
+    #[serde(skip)]
+    sha256_base64: String,
+    pub size: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// Relative paths are resolved against the manifest directory.
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub default: bool,
+}
+
+impl Manifest {
+    fn read(manifest_path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(manifest_path)
+            .with_context(|| format!("Could not read file manifest {}", manifest_path.display()))?;
+        toml::from_str(&raw)
+            .with_context(|| format!("Invalid file manifest {}", manifest_path.display()))
+    }
+
+    /// Falls back to [`Manifest::legacy`] while the manifest does not exist.
+    fn read_or_legacy(manifest_path: &Path) -> anyhow::Result<Self> {
+        if !manifest_path.exists() {
+            return Ok(Self::legacy());
+        }
+        Self::read(manifest_path)
+    }
+
+    /// Files hosted before the manifest was introduced, so upgrading without creating one keeps
+    /// the downloads working.
+    fn legacy() -> Self {
+        let entry = |name: &str, path: &str, default| ManifestEntry {
+            name: String::from(name),
+            path: PathBuf::from(path),
+            content_type: None,
+            description: String::new(),
+            default,
+        };
         Self {
+            files: vec![
+                entry("BClickerDownloader", "BClickerDownloader.zip", true),
+                entry("BuzkaaClicker", "BuzkaaClicker-v16.rar", false),
+            ],
+        }
+    }
+}
+
+impl FileHost {
+    /// Serves the legacy files if the manifest does not exist yet, it is picked up by
+    /// [`FileHost::reload_if_modified`] once created.
+    pub fn load(pg: Pool<Sqlite>, manifest_path: PathBuf) -> anyhow::Result<Self> {
+        if !manifest_path.exists() {
+            warn!(
+                "File manifest {} does not exist, serving the legacy files next to it.",
+                manifest_path.display()
+            );
+        }
+        let table = FileTable::load(&manifest_path, None)?;
+        Ok(Self {
             pg,
-            default_file,
-            files: files_lowercase,
+            manifest_path,
+            table: RwLock::new(Arc::new(table)),
+            manifest_lock: Default::default(),
+        })
+    }
+
+    fn table(&self) -> Arc<FileTable> {
+        Arc::clone(&self.table.read().expect("File table poisoned!"))
+    }
+
+    fn swap_table(&self, table: FileTable) {
+        *self.table.write().expect("File table poisoned!") = Arc::new(table);
+    }
+
+    /// Directory which relative manifest paths are resolved against.
+    pub fn base_dir(&self) -> &Path {
+        self.manifest_path.parent().unwrap_or(Path::new("."))
+    }
+
+    /// Adds the file to the manifest, replacing the one with the same name.
+    pub async fn register(&self, entry: ManifestEntry) -> anyhow::Result<()> {
+        self.edit_manifest(move |manifest| {
+            if entry.default {
+                manifest
+                    .files
+                    .iter_mut()
+                    .for_each(|file| file.default = false);
+            }
+            manifest
+                .files
+                .retain(|file| !file.name.eq_ignore_ascii_case(&entry.name));
+            manifest.files.push(entry);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the file from the manifest, the file itself is kept on disk.
+    pub async fn retire(&self, name: &str) -> anyhow::Result<()> {
+        let name = name.to_owned();
+        self.edit_manifest(move |manifest| {
+            let file = manifest
+                .files
+                .iter()
+                .find(|file| file.name.eq_ignore_ascii_case(&name))
+                .with_context(|| format!("File '{name}' is not hosted"))?;
+            if file.default {
+                bail!("Default file '{}' cannot be retired", file.name);
+            }
+            manifest
+                .files
+                .retain(|file| !file.name.eq_ignore_ascii_case(&name));
+            Ok(())
+        })
+        .await
+    }
+
+    /// Applies the edit to the manifest on disk. The edited manifest is validated before it
+    /// replaces the old one, so a bad edit leaves both the manifest and the file table intact.
+    async fn edit_manifest<F>(&self, edit: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut Manifest) -> anyhow::Result<()> + Send + 'static,
+    {
+        let _guard = self.manifest_lock.lock().await;
+        let previous = self.table();
+        let manifest_path = self.manifest_path.clone();
+        let table = task::spawn_blocking(move || {
+            let mut manifest = Manifest::read_or_legacy(&manifest_path)?;
+            edit(&mut manifest)?;
+            let edited_path = manifest_path.with_extension("toml.edit");
+            let edited =
+                toml::to_string_pretty(&manifest).context("Could not serialize manifest")?;
+            std::fs::write(&edited_path, edited).context("Could not write edited manifest")?;
+            let table = FileTable::load(&edited_path, Some(&previous));
+            if table.is_ok() {
+                std::fs::rename(&edited_path, &manifest_path)
+                    .context("Could not replace manifest")?;
+            } else {
+                let _ = std::fs::remove_file(&edited_path);
+            }
+            table
+        })
+        .await
+        .context("Manifest edit task panicked")??;
+        self.swap_table(table);
+        Ok(())
+    }
+
+    /// Reloads the manifest if it or any of the hosted files were modified since the last load.
+    /// Returns whether the file table was swapped.
+    pub async fn reload_if_modified(&self) -> anyhow::Result<bool> {
+        let table = self.table();
+        let manifest_path = self.manifest_path.clone();
+        let reloaded = task::spawn_blocking(move || {
+            if !table.is_modified(&manifest_path)? {
+                return Ok(None);
+            }
+            FileTable::load(&manifest_path, Some(&table)).map(Some)
+        })
+        .await
+        .context("Manifest reload task panicked")??;
+        match reloaded {
+            None => Ok(false),
+            Some(table) => {
+                self.swap_table(table);
+                Ok(true)
+            }
         }
     }
 
+    pub fn list(&self) -> Vec<FileListing> {
+        let table = self.table();
+        let mut listing: Vec<_> = table
+            .files
+            .values()
+            .map(|file| FileListing {
+                name: file.name.clone(),
+                description: file.description.clone(),
+                content_type: file.content_type.as_ref().map(Mime::to_string),
+                default: file.name == table.default_file,
+                integrity: file.integrity.clone(),
+            })
+            .collect();
+        listing.sort_by(|a, b| a.name.cmp(&b.name));
+        listing
+    }
+
+    /// Canonical name of the file and its integrity metadata,
+    /// `None` if the file is not hosted or could not be hashed.
+    pub fn integrity(&self, file_name: &str) -> Option<(String, FileIntegrity)> {
+        let table = self.table();
+        let file = table.files.get(&file_name.to_lowercase())?;
+        let integrity = file.integrity.clone()?;
+        Some((file.name.clone(), integrity))
+    }
+
     pub async fn download(
         &self,
         ip: &str,
         file_name: &Option<&str>,
-    ) -> actix_web::Result<NamedFile> {
-        let file_name = file_name.unwrap_or(&self.default_file);
-        let file_path = self
+    ) -> actix_web::Result<(NamedFile, Option<FileIntegrity>)> {
+        let table = self.table();
+        let file_name = file_name.unwrap_or(&table.default_file);
+        let file = table
             .files
             .get(&file_name.to_lowercase())
             .ok_or(actix_web::error::ErrorNotFound("file not found"))?;
-        if let Err(err) = self.insert_stat(ip, file_name).await {
+        if let Err(err) = self.insert_stat(ip, &file.name).await {
             error!("Could not insert download statistic to db: {:#}", err);
         }
-        NamedFile::open_async(file_path)
+        let named_file = NamedFile::open_async(&file.path)
             .await
-            .map(|file| file.use_etag(true).use_last_modified(true))
+            .map(|named_file| {
+                let named_file = named_file.use_etag(true).use_last_modified(true);
+                match &file.content_type {
+                    Some(content_type) => named_file.set_content_type(content_type.clone()),
+                    None => named_file,
+                }
+            })
             .inspect_err(|err| error!("Could not open named file: {err}"))
-            .map_err(|_| actix_web::error::ErrorInternalServerError("could not serve file"))
+            .map_err(|_| actix_web::error::ErrorInternalServerError("could not serve file"))?;
+        // the file may have been replaced since the integrity was computed on the last reload
+        let metadata = named_file.metadata();
+        let stamp = FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        };
+        let integrity = if file.stamp == Some(stamp) {
+            file.integrity.clone()
+        } else {
+            warn!(
+                "Hosted file '{}' changed since the last reload, serving it without integrity.",
+                file.name
+            );
+            None
+        };
+        Ok((named_file, integrity))
     }
 
     async fn insert_stat(&self, ip: &str, file_name: &str) -> anyhow::Result<()> {
@@ -57,15 +328,356 @@ impl FileHost {
     }
 }
 
+impl FileTable {
+    /// Integrity of files which did not change since `previous` table is reused.
+    fn load(manifest_path: &Path, previous: Option<&FileTable>) -> anyhow::Result<Self> {
+        let manifest_modified = manifest_modified(manifest_path)?;
+        let manifest = Manifest::read_or_legacy(manifest_path)?;
+        let base_dir = manifest_path.parent().unwrap_or(Path::new("."));
+
+        let mut default_file = None;
+        let mut files = HashMap::with_capacity(manifest.files.len());
+        for entry in manifest.files {
+            let content_type = entry
+                .content_type
+                .map(|content_type| content_type.parse::<Mime>())
+                .transpose()
+                .with_context(|| format!("Invalid content type of file '{}'", entry.name))?;
+            if entry.default {
+                if let Some(previous) = default_file.replace(entry.name.clone()) {
+                    bail!(
+                        "Both '{previous}' and '{}' are marked as default",
+                        entry.name
+                    );
+                }
+            }
+            let path = base_dir.join(entry.path);
+            let stamp = file_stamp(&path);
+            let integrity = match stamp {
+                None => {
+                    warn!(
+                        "Hosted file '{}' does not exist at {}.",
+                        entry.name,
+                        path.display()
+                    );
+                    None
+                }
+                Some(stamp) => previous
+                    .and_then(|previous| previous.cached_integrity(&path, stamp))
+                    .or_else(|| match compute_integrity(&path) {
+                        Ok(integrity) => Some(integrity),
+                        Err(err) => {
+                            error!("Could not compute integrity of {}: {err}", path.display());
+                            None
+                        }
+                    }),
+            };
+            let file = HostedFile {
+                name: entry.name,
+                path,
+                content_type,
+                description: entry.description,
+                stamp,
+                integrity,
+            };
+            if let Some(duplicate) = files.insert(file.name.to_lowercase(), file) {
+                bail!("File '{}' is listed more than once", duplicate.name);
+            }
+        }
+        let default_file = default_file.context("No file is marked as default")?;
+        Ok(Self {
+            manifest_modified,
+            default_file,
+            files,
+        })
+    }
+
+    fn is_modified(&self, manifest_path: &Path) -> anyhow::Result<bool> {
+        if manifest_modified(manifest_path)? != self.manifest_modified {
+            return Ok(true);
+        }
+        Ok(self
+            .files
+            .values()
+            .any(|file| file_stamp(&file.path) != file.stamp))
+    }
+
+    fn cached_integrity(&self, path: &Path, stamp: FileStamp) -> Option<FileIntegrity> {
+        self.files
+            .values()
+            .find(|file| file.path == path && file.stamp == Some(stamp))
+            .and_then(|file| file.integrity.clone())
+    }
+}
+
+/// `None` if the manifest does not exist.
+fn manifest_modified(manifest_path: &Path) -> anyhow::Result<Option<SystemTime>> {
+    let metadata = match std::fs::metadata(manifest_path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        metadata => metadata
+            .with_context(|| format!("Could not stat file manifest {}", manifest_path.display()))?,
+    };
+    Ok(metadata.modified().ok())
+}
+
+fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = std::fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())?;
+    Some(FileStamp {
+        modified: metadata.modified().ok(),
+        len: metadata.len(),
+    })
+}
+
+fn compute_integrity(path: &Path) -> io::Result<FileIntegrity> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    let digest = hasher.finalize();
+    Ok(FileIntegrity {
+        sha256: format!("{digest:x}"),
+        sha256_base64: BASE64_STANDARD.encode(digest),
+        size,
+    })
+}
+
+pub async fn watch_manifest(file_host: web::Data<FileHost>, reload_interval: Duration) {
+    let mut reload_interval = time::interval(reload_interval);
+    reload_interval.tick().await;
+    loop {
+        reload_interval.tick().await;
+        match file_host.reload_if_modified().await {
+            Ok(true) => info!("Reloaded file manifest."),
+            Ok(false) => {}
+            Err(err) => error!("Could not reload file manifest, keeping old one: {err:#}"),
+        }
+    }
+}
+
+#[get("/files")]
+pub async fn list_files(file_host: web::Data<FileHost>) -> impl Responder {
+    web::Json(file_host.list())
+}
+
 pub async fn download_specific(
     req: HttpRequest,
     file_host: web::Data<FileHost>,
-) -> actix_web::Result<NamedFile> {
+) -> actix_web::Result<HttpResponse> {
     let file_name = req.match_info().get("file");
     let ip = req
         .connection_info()
         .realip_remote_addr()
         .expect("Request ip must be present!")
         .to_owned();
-    file_host.download(&ip, &file_name).await
+    let (named_file, integrity) = file_host.download(&ip, &file_name).await?;
+    let mut response = named_file.into_response(&req);
+    if let Some(integrity) = integrity {
+        let headers = response.headers_mut();
+        // RFC 9530, the digest covers the whole file even for range requests.
+        headers.insert(
+            HeaderName::from_static("repr-digest"),
+            HeaderValue::try_from(format!("sha-256=:{}:", integrity.sha256_base64))?,
+        );
+        // RFC 3230, for older downloaders.
+        headers.insert(
+            HeaderName::from_static("digest"),
+            HeaderValue::try_from(format!("SHA-256={}", integrity.sha256_base64))?,
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn write_manifest(dir: &Path, manifest: &str, modified: SystemTime) -> PathBuf {
+        let path = dir.join("manifest.toml");
+        std::fs::write(&path, manifest).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        path
+    }
+
+    #[actix_web::test]
+    pub async fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("bclicker-filehost-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = r#"
+            [[files]]
+            name = "BClickerDownloader"
+            path = "BClickerDownloader.zip"
+            default = true
+        "#;
+        let manifest_path = write_manifest(&dir, manifest, SystemTime::UNIX_EPOCH);
+        let pg = Pool::connect_lazy("sqlite::memory:").unwrap();
+        let file_host = FileHost::load(pg, manifest_path).unwrap();
+        assert_eq!(file_host.list().len(), 1);
+        assert!(!file_host.reload_if_modified().await.unwrap());
+
+        let manifest = format!(
+            r#"{manifest}
+            [[files]]
+            name = "BuzkaaClicker"
+            path = "BuzkaaClicker-v17.rar"
+            content_type = "application/vnd.rar"
+            "#
+        );
+        write_manifest(&dir, &manifest, SystemTime::now());
+        assert!(file_host.reload_if_modified().await.unwrap());
+        let listing = file_host.list();
+        assert_eq!(listing.len(), 2);
+        assert_eq!(listing[1].name, "BuzkaaClicker");
+        assert_eq!(
+            listing[1].content_type.as_deref(),
+            Some("application/vnd.rar")
+        );
+
+        let manifest = manifest.replace("content_type", "default = true\ncontent_type");
+        write_manifest(&dir, &manifest, SystemTime::now() + Duration::from_secs(1));
+        assert!(file_host.reload_if_modified().await.is_err());
+        assert_eq!(file_host.list().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    pub async fn test_missing_manifest() {
+        let dir = std::env::temp_dir().join(format!("bclicker-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pg = Pool::connect_lazy("sqlite::memory:").unwrap();
+        let file_host = FileHost::load(pg, dir.join("manifest.toml")).unwrap();
+        let legacy = file_host.list();
+        assert_eq!(legacy.len(), 2);
+        assert_eq!(legacy[0].name, "BClickerDownloader");
+        assert!(legacy[0].default);
+        assert_eq!(legacy[1].name, "BuzkaaClicker");
+        assert_eq!(
+            file_host.table().files["buzkaaclicker"].path,
+            dir.join("BuzkaaClicker-v16.rar")
+        );
+        assert!(!file_host.reload_if_modified().await.unwrap());
+
+        let manifest = r#"
+            [[files]]
+            name = "BClickerDownloader"
+            path = "BClickerDownloader.zip"
+            default = true
+        "#;
+        write_manifest(&dir, manifest, SystemTime::now());
+        assert!(file_host.reload_if_modified().await.unwrap());
+        assert_eq!(file_host.list().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    pub async fn test_integrity() {
+        let dir = std::env::temp_dir().join(format!("bclicker-integrity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("BClickerDownloader.zip"), "abc").unwrap();
+        let manifest = r#"
+            [[files]]
+            name = "BClickerDownloader"
+            path = "BClickerDownloader.zip"
+            default = true
+        "#;
+        let manifest_path = write_manifest(&dir, manifest, SystemTime::UNIX_EPOCH);
+        let pg = Pool::connect_lazy("sqlite::memory:").unwrap();
+        let file_host = FileHost::load(pg, manifest_path).unwrap();
+        let (name, integrity) = file_host.integrity("bclickerdownloader").unwrap();
+        assert_eq!(name, "BClickerDownloader");
+        assert_eq!(
+            integrity.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(integrity.size, 3);
+        assert!(!file_host.reload_if_modified().await.unwrap());
+        let (_, integrity) = file_host.download("127.0.0.1", &None).await.unwrap();
+        assert_eq!(integrity.unwrap().size, 3);
+
+        std::fs::write(dir.join("BClickerDownloader.zip"), "abcd").unwrap();
+        // replaced after the last reload, the cached integrity is of the old contents
+        let (_, integrity) = file_host.download("127.0.0.1", &None).await.unwrap();
+        assert!(integrity.is_none());
+        assert!(file_host.reload_if_modified().await.unwrap());
+        assert_eq!(file_host.list()[0].integrity.as_ref().unwrap().size, 4);
+        let (_, integrity) = file_host.download("127.0.0.1", &None).await.unwrap();
+        assert_eq!(integrity.unwrap().size, 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    pub async fn test_download_stat() {
+        let dir = std::env::temp_dir().join(format!("bclicker-stat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("BClickerDownloader.zip"), "abc").unwrap();
+        let manifest = r#"
+            [[files]]
+            name = "BClickerDownloader"
+            path = "BClickerDownloader.zip"
+            default = true
+        "#;
+        let manifest_path = write_manifest(&dir, manifest, SystemTime::UNIX_EPOCH);
+        let pg = db::test_pool().await;
+        let file_host = FileHost::load(Pool::clone(&pg), manifest_path).unwrap();
+        for file_name in [None, Some("bclickerdownloader"), Some("BCLICKERDOWNLOADER")] {
+            file_host.download("127.0.0.1", &file_name).await.unwrap();
+        }
+        let files: Vec<String> = sqlx::query_scalar("select file from downloads;")
+            .fetch_all(&pg)
+            .await
+            .unwrap();
+        assert_eq!(files, ["BClickerDownloader"; 3]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    pub async fn test_register_and_retire() {
+        let dir = std::env::temp_dir().join(format!("bclicker-register-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = r#"
+            [[files]]
+            name = "BClickerDownloader"
+            path = "BClickerDownloader.zip"
+            default = true
+        "#;
+        let manifest_path = write_manifest(&dir, manifest, SystemTime::UNIX_EPOCH);
+        let pg = Pool::connect_lazy("sqlite::memory:").unwrap();
+        let file_host = FileHost::load(pg, manifest_path.clone()).unwrap();
+        std::fs::write(dir.join("BuzkaaClicker-v17.rar"), "rar").unwrap();
+        file_host
+            .register(ManifestEntry {
+                name: String::from("BuzkaaClicker"),
+                path: PathBuf::from("BuzkaaClicker-v17.rar"),
+                content_type: None,
+                description: String::new(),
+                default: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(file_host.integrity("buzkaaclicker").unwrap().1.size, 3);
+        assert!(!file_host.reload_if_modified().await.unwrap());
+        assert!(file_host.retire("BClickerDownloader").await.is_err());
+
+        let reloaded = FileHost::load(
+            Pool::connect_lazy("sqlite::memory:").unwrap(),
+            manifest_path,
+        )
+        .unwrap();
+        assert_eq!(reloaded.list().len(), 2);
+
+        file_host.retire("buzkaaclicker").await.unwrap();
+        assert!(file_host.integrity("BuzkaaClicker").is_none());
+        assert!(dir.join("BuzkaaClicker-v17.rar").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
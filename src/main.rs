@@ -1,7 +1,15 @@
 use crate::bc::{ChartJson, DownloadCount};
+use crate::config::Config;
+use crate::download_stats::DownloadStatsJson;
+use crate::events::Events;
 use crate::file_host::FileHost;
-use crate::online_users::OnlineUsers;
-use crate::yt::LiveJson;
+use crate::notifications::Notifier;
+use crate::online_stats::OnlineStatsJson;
+use crate::online_users::MinuteRetention;
+use crate::online_versions::VersionHistoryJson;
+use crate::releases::ReleaseCatalog;
+use crate::streams::StreamsJson;
+use crate::yt::{LiveChannels, LiveProviders};
 use actix_extensible_rate_limit::backend::memory::InMemoryBackend;
 use actix_extensible_rate_limit::backend::{SimpleInputFunctionBuilder, SimpleOutput};
 use actix_extensible_rate_limit::RateLimiter;
@@ -12,19 +20,30 @@ use actix_web::rt::spawn;
 use actix_web::web::Data;
 use actix_web::{get, guard, middleware, web, App, HttpResponse, HttpServer, Responder};
 use anyhow::Context;
-use log::info;
+use log::{error, info};
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::{Pool, Sqlite};
-use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+mod admin;
 mod bc;
 mod cache;
+mod config;
+mod date_range;
+#[cfg(test)]
+mod db;
+mod download_stats;
+mod events;
 mod file_host;
+mod notifications;
+mod online_stats;
 mod online_users;
+mod online_versions;
+mod releases;
+mod streams;
 mod yt;
 
 pub mod built_info {
@@ -40,28 +59,110 @@ async fn main() -> anyhow::Result<()> {
         .format_timestamp_millis()
         .init();
 
-    let bc_version: u32 = env::var("BUZKAACLICKER_VERSION")
-        .context("Invalid BUZKAACLICKER_VERSION env variable!")?
-        .parse()
-        .context("BUZKAACLICKER_VERSION is not a u32 number!")?;
-    let bc_version = bc::Version(bc_version);
+    let config = Config::load().context("Could not load config!")?;
+    let bc_version = bc::Version(config.buzkaaclicker.version);
 
     info!("Establishing sqlite connection.");
-    let pg = create_sqlite_pool()
+    let pg = create_sqlite_pool(&config.database.path)
         .await
         .expect("Could not create sqlite connection!");
     info!("Established sqlite connection.");
 
-    let online_users = Data::new(Mutex::new(OnlineUsers::new()));
+    let online_users = Data::new(Mutex::new(
+        online_users::load_presence(&pg)
+            .await
+            .context("Could not restore online users!")?,
+    ));
     spawn(online_users::start_archiving(
         Pool::clone(&pg),
         Data::clone(&online_users),
     ));
+    spawn(online_users::start_saving_presence(
+        Pool::clone(&pg),
+        Data::clone(&online_users),
+        Duration::from_secs(config.online_users.presence_save_interval_secs),
+    ));
+    let events = Data::new(Events::new());
+    spawn(events::start_online_events(
+        Data::clone(&online_users),
+        Data::clone(&events),
+        Duration::from_secs(config.online_users.push_interval_secs),
+    ));
+    spawn(online_users::start_rollups(
+        Pool::clone(&pg),
+        config.online_users.minute_retention_days,
+        Duration::from_secs(config.online_users.rollup_interval_secs),
+    ));
     let chart_json = Data::new(ChartJson::memoized(Pool::clone(&pg)).await);
-    let file_host = create_file_host(Pool::clone(&pg));
+    let minute_retention = MinuteRetention(config.online_users.minute_retention_days);
+    let chart_by_query = Data::new(ChartJson::memoized_by_query(
+        Pool::clone(&pg),
+        minute_retention,
+    ));
+    let file_host = Data::new(
+        FileHost::load(Pool::clone(&pg), config.file_host.manifest.clone())
+            .context("Could not load file host manifest!")?,
+    );
+    spawn(file_host::watch_manifest(
+        Data::clone(&file_host),
+        Duration::from_secs(config.file_host.reload_interval_secs),
+    ));
     let rate_limiter_backend = InMemoryBackend::builder().build();
-    let live_json = Data::new(LiveJson::memoized().await);
+    let live_providers =
+        LiveProviders::from_config(&config.youtube).context("Could not set up live providers!")?;
+    let notifier = Arc::new(Notifier::new(
+        Pool::clone(&pg),
+        config.youtube.webhooks.clone(),
+        Duration::from_secs(5),
+    ));
+    let live_channels = Data::new(
+        LiveChannels::memoized(
+            live_providers,
+            Pool::clone(&pg),
+            notifier,
+            &config.youtube.channels,
+        )
+        .await,
+    );
     let download_counter = Data::new(DownloadCount::memoized(Pool::clone(&pg)).await);
+    let download_stats = Data::new(DownloadStatsJson::memoized(Pool::clone(&pg)).await);
+    let online_stats = Data::new(OnlineStatsJson::memoized_by_tz(Pool::clone(&pg)));
+    let version_history = Data::new(VersionHistoryJson::memoized_by_query(Pool::clone(&pg)));
+    let download_stats_by_query = Data::new(DownloadStatsJson::memoized_by_query(Pool::clone(&pg)));
+    let release_catalog = Data::new(ReleaseCatalog::new(Pool::clone(&pg)));
+    let streams = Data::new(StreamsJson::memoized_by_query(Pool::clone(&pg)));
+    let shutdown_refreshers = {
+        let live_channels = Data::clone(&live_channels);
+        let chart_json = Data::clone(&chart_json);
+        let download_counter = Data::clone(&download_counter);
+        let download_stats = Data::clone(&download_stats);
+        async move {
+            live_channels.shutdown().await;
+            chart_json.shutdown().await;
+            download_counter.shutdown().await;
+            download_stats.shutdown().await;
+        }
+    };
+    let save_presence = {
+        let pg = Pool::clone(&pg);
+        let online_users = Data::clone(&online_users);
+        async move {
+            match online_users::save_presence(&pg, &online_users).await {
+                Ok(count) => info!("Saved presence of {count} clients."),
+                Err(err) => error!("Could not save presence: {err:#}."),
+            }
+        }
+    };
+    let api_hosts = config.server.api_hosts.clone();
+    let site_hosts = config.server.site_hosts.clone();
+    let static_dir = config.server.static_dir.clone();
+    let admin_config = config.admin.clone();
+    let live_paths: Vec<String> = config
+        .youtube
+        .channels
+        .iter()
+        .map(|channel| format!("/youtube/{}", channel.slug))
+        .collect();
 
     HttpServer::new(move || {
         let download_rate_limiter = RateLimiter::builder(
@@ -72,28 +173,62 @@ async fn main() -> anyhow::Result<()> {
         )
         .request_denied_response(rate_limited)
         .build();
+        // Shares the backend with downloads, the custom key keeps their counters apart.
+        let heartbeat_rate_limiter = RateLimiter::builder(
+            rate_limiter_backend.clone(),
+            SimpleInputFunctionBuilder::new(Duration::from_secs(60), 120)
+                .custom_key("heartbeat")
+                .real_ip_key()
+                .build(),
+        )
+        .request_denied_response(rate_limited)
+        .build();
         App::new()
             .app_data(Data::clone(&file_host))
             .service(
                 web::scope("")
-                    .guard(
-                        guard::Any(guard::Host("apiv2.makin.cc"))
-                            .or(guard::Host("buzkaaclickerapi.firma.sex.pl")),
-                    )
+                    .guard(hosts_guard(&api_hosts))
                     .app_data(Data::clone(&online_users))
                     .app_data(Data::new(Pool::clone(&pg)))
-                    .app_data(Data::clone(&live_json))
+                    .app_data(Data::clone(&live_channels))
                     .app_data(Data::clone(&download_counter))
+                    .app_data(Data::clone(&download_stats))
+                    .app_data(Data::clone(&download_stats_by_query))
+                    .app_data(Data::clone(&online_stats))
+                    .app_data(Data::clone(&version_history))
+                    .app_data(Data::clone(&events))
+                    .app_data(Data::clone(&release_catalog))
+                    .app_data(Data::clone(&streams))
                     .wrap(DefaultHeaders::new().add(("Access-Control-Allow-Origin", "*")))
                     .service(index)
                     .configure(|app_config| {
                         for path in ["/buzkaaClicker", "/buzkaaclicker"] {
+                            app_config.service(
+                                web::resource(format!("{path}/heartbeat"))
+                                    .route(web::post().to(bc::heartbeat))
+                                    .wrap(heartbeat_rate_limiter.clone()),
+                            );
                             app_config.service(web::scope(path).configure(|config| {
-                                bc::configure_service(bc_version, &chart_json, config)
+                                bc::configure_service(
+                                    bc_version,
+                                    minute_retention,
+                                    &chart_json,
+                                    &chart_by_query,
+                                    config,
+                                )
                             }));
                         }
                     })
-                    .service(web::scope("/youtube").service(yt::live))
+                    .service(
+                        web::scope("/youtube")
+                            .service(yt::get_live_channels)
+                            .service(streams::list_streams)
+                            .service(yt::live),
+                    )
+                    .service(file_host::list_files)
+                    .service(web::scope("/admin").configure(|config| {
+                        admin::configure_service(admin_config.clone(), config)
+                    }))
                     .service(
                         web::resource(["/download", "/download/", "/download/{file}"])
                             .route(web::get().to(file_host::download_specific))
@@ -102,54 +237,46 @@ async fn main() -> anyhow::Result<()> {
             )
             .service(
                 web::scope("")
-                    .guard(
-                        guard::Any(guard::Host("buzkaaclicker.pl"))
-                            .or(guard::Host("buzkaaclicker.firma.sex.pl")),
-                    )
+                    .guard(hosts_guard(&site_hosts))
                     .service(
                         web::resource(["/download", "/download/", "/download/{file}"])
                             .route(web::get().to(file_host::download_specific))
                             .wrap(download_rate_limiter),
                     )
-                    .service(Files::new("/", "./static").index_file("index.html")),
+                    .service(Files::new("/", &static_dir).index_file("index.html")),
             )
-            .wrap(
+            .wrap(live_paths.iter().fold(
                 middleware::Logger::new(
                     r#"%a (%{r}a) "%r" %s %b "%{Host}i" "%{Referer}i" "%{User-Agent}i" %T"#,
-                )
-                .exclude("/youtube/Buzkaa"),
-            )
+                ),
+                |logger, path| logger.exclude(path),
+            ))
     })
-    .bind(("0.0.0.0", 2137))?
+    .bind(config.server.bind)?
     .run()
     .await?;
+
+    info!("Server stopped, shutting down background refreshers.");
+    shutdown_refreshers.await;
+    save_presence.await;
     Ok(())
 }
 
-fn create_file_host(pg: Pool<Sqlite>) -> Data<FileHost> {
-    let files = HashMap::from([
-        (
-            "BClickerDownloader".into(),
-            PathBuf::from("./filehost/BClickerDownloader.zip"),
-        ),
-        (
-            "BuzkaaClicker".into(),
-            PathBuf::from("./filehost/BuzkaaClicker-v16.rar"),
-        ),
-    ]);
-    Data::new(FileHost::new(
-        Pool::clone(&pg),
-        String::from("BClickerDownloader"),
-        files,
-    ))
+fn hosts_guard(hosts: &[String]) -> guard::AnyGuard {
+    hosts
+        .iter()
+        .skip(1)
+        .fold(guard::Any(guard::Host(&hosts[0])), |any, host| {
+            any.or(guard::Host(host))
+        })
 }
 
-async fn create_sqlite_pool() -> anyhow::Result<Pool<Sqlite>> {
+async fn create_sqlite_pool(path: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let pool = SqlitePoolOptions::new()
         .max_connections(10)
         .connect_with(
             SqliteConnectOptions::new()
-                .filename("db.sqlite")
+                .filename(path)
                 .create_if_missing(true),
         )
         .await
@@ -0,0 +1,633 @@
+use crate::config::{ChannelConfig, WebhookConfig, WebhookFormat};
+use actix_web::rt::{spawn, time};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Stream a notification is about.
+#[derive(Clone, Debug)]
+pub struct LiveStream {
+    pub video_id: String,
+    pub title: String,
+    pub started_at: DateTime<Utc>,
+    pub thumbnail_url: Option<String>,
+    pub viewers: Option<u32>,
+}
+
+impl LiveStream {
+    fn url(&self) -> String {
+        format!("https://www.youtube.com/watch?v={}", self.video_id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    Live,
+    Offline,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Live => "live",
+            Event::Offline => "offline",
+        }
+    }
+}
+
+struct Notification {
+    event: Event,
+    channel: ChannelConfig,
+    stream: LiveStream,
+}
+
+impl Notification {
+    fn payload(&self, format: WebhookFormat) -> Value {
+        let Notification {
+            event,
+            channel,
+            stream,
+        } = self;
+        match (format, event) {
+            (WebhookFormat::Discord, Event::Live) => {
+                let mut embed = json!({
+                    "title": stream.title,
+                    "url": stream.url(),
+                    "author": { "name": channel.name },
+                    "timestamp": stream.started_at,
+                });
+                if let Some(url) = &stream.thumbnail_url {
+                    embed["image"] = json!({ "url": url });
+                }
+                json!({
+                    "content": format!("{} jest na żywo! {}", channel.name, stream.url()),
+                    "embeds": [embed],
+                })
+            }
+            (WebhookFormat::Discord, Event::Offline) => json!({
+                "content": format!("{} zakończył stream: {}", channel.name, stream.title),
+            }),
+            (WebhookFormat::Generic, _) => json!({
+                "event": event.as_str(),
+                "channel": {
+                    "id": channel.id,
+                    "name": channel.name,
+                    "slug": channel.slug,
+                },
+                "stream": {
+                    "videoId": stream.video_id,
+                    "url": stream.url(),
+                    "title": stream.title,
+                    "startedAt": stream.started_at,
+                    "thumbnailUrl": stream.thumbnail_url,
+                    "viewers": stream.viewers,
+                },
+            }),
+        }
+    }
+}
+
+/// Notifies the webhooks when a channel goes live or offline. Each webhook gets a video's live
+/// event once and then its offline event once, even across restarts.
+pub struct Notifier {
+    pg: Pool<Sqlite>,
+    /// Last observed stream by channel id, missing until the first observation.
+    live: Mutex<HashMap<String, Option<LiveStream>>>,
+    /// Notifications waiting for each webhook.
+    queues: Vec<mpsc::UnboundedSender<Arc<Notification>>>,
+}
+
+impl Notifier {
+    /// Starts delivering in the background, `retry_delay` is doubled after each failed attempt.
+    pub fn new(pg: Pool<Sqlite>, webhooks: Vec<WebhookConfig>, retry_delay: Duration) -> Self {
+        let queues = webhooks
+            .into_iter()
+            .map(|webhook| {
+                let (queue, notifications) = mpsc::unbounded();
+                let deliveries = Deliveries {
+                    pg: Pool::clone(&pg),
+                    webhook,
+                    retry_delay,
+                };
+                spawn(deliveries.run(notifications));
+                queue
+            })
+            .collect();
+        Self {
+            pg,
+            live: Mutex::new(HashMap::new()),
+            queues,
+        }
+    }
+
+    /// Records the channel's current stream, `None` while it's offline, and notifies the
+    /// webhooks in the background if that's a change.
+    pub async fn observe(&self, channel: &ChannelConfig, stream: Option<LiveStream>) {
+        let previous = self
+            .live
+            .lock()
+            .expect("Notifier live streams poisoned!")
+            .insert(channel.id.clone(), stream.clone());
+        let events = match (previous, stream) {
+            (Some(Some(previous)), Some(stream)) if previous.video_id == stream.video_id => return,
+            // switched streams without being seen offline in between
+            (Some(Some(previous)), Some(stream)) => {
+                vec![(Event::Offline, previous), (Event::Live, stream)]
+            }
+            // first observation since the start, streams that ended while the server was down
+            // still need their offline event, the deliveries tell what was notified already
+            (None, stream) => {
+                let current = stream.as_ref().map(|stream| stream.video_id.as_str());
+                let mut events = match self.ended_unnotified(channel, current).await {
+                    Ok(ended) => ended
+                        .into_iter()
+                        .map(|ended| (Event::Offline, ended))
+                        .collect(),
+                    Err(err) => {
+                        error!(
+                            "Could not select ended streams of {}: {err:#}",
+                            channel.slug
+                        );
+                        Vec::new()
+                    }
+                };
+                events.extend(stream.map(|stream| (Event::Live, stream)));
+                events
+            }
+            (Some(_), Some(stream)) => vec![(Event::Live, stream)],
+            (Some(Some(previous)), None) => vec![(Event::Offline, previous)],
+            (Some(None), None) => return,
+        };
+        for (event, stream) in events {
+            info!(
+                "Channel {} went {} ({}).",
+                channel.slug,
+                event.as_str(),
+                stream.video_id
+            );
+            let notification = Arc::new(Notification {
+                event,
+                channel: channel.clone(),
+                stream,
+            });
+            for queue in &self.queues {
+                if queue.unbounded_send(Arc::clone(&notification)).is_err() {
+                    error!("Webhook delivery stopped, dropping notification.");
+                }
+            }
+        }
+    }
+
+    /// Recorded streams of the channel other than `current` some webhook got the live event of
+    /// but not the offline one.
+    async fn ended_unnotified(
+        &self,
+        channel: &ChannelConfig,
+        current: Option<&str>,
+    ) -> anyhow::Result<Vec<LiveStream>> {
+        let rows = sqlx::query(
+            "select video_id, title, started_at from streams \
+             where channel_id = ? and video_id is not ? and exists ( \
+                 select 1 from webhook_deliveries live \
+                 where live.video_id = streams.video_id and live.event = 'live' \
+                     and live.delivered and not exists ( \
+                         select 1 from webhook_deliveries offline \
+                         where offline.webhook = live.webhook \
+                             and offline.video_id = live.video_id \
+                             and offline.event = 'offline' and offline.delivered \
+                     ) \
+             ) \
+             order by started_at;",
+        )
+        .bind(&channel.id)
+        .bind(current)
+        .fetch_all(&self.pg)
+        .await
+        .context("Could not select streams")?;
+        Ok(rows
+            .into_iter()
+            .map(|row| LiveStream {
+                video_id: row.get("video_id"),
+                title: row.get("title"),
+                started_at: row.get::<NaiveDateTime, _>("started_at").and_utc(),
+                thumbnail_url: None,
+                viewers: None,
+            })
+            .collect())
+    }
+}
+
+/// Delivers the notifications of one webhook one after another, so they arrive in the order
+/// they were observed.
+struct Deliveries {
+    pg: Pool<Sqlite>,
+    webhook: WebhookConfig,
+    retry_delay: Duration,
+}
+
+impl Deliveries {
+    async fn run(self, mut notifications: mpsc::UnboundedReceiver<Arc<Notification>>) {
+        while let Some(notification) = notifications.next().await {
+            if let Err(err) = self.notify(&notification).await {
+                error!("Could not notify webhook {}: {err:#}", self.webhook.name);
+            }
+        }
+    }
+
+    async fn notify(&self, notification: &Notification) -> anyhow::Result<()> {
+        // a video goes live and offline once, flickering between the two isn't repeated
+        let due = match notification.event {
+            Event::Live => !self.delivered(notification, Event::Live).await?,
+            Event::Offline => {
+                self.delivered(notification, Event::Live).await?
+                    && !self.delivered(notification, Event::Offline).await?
+            }
+        };
+        if !due {
+            debug!(
+                "Webhook {} doesn't need {} of {}.",
+                self.webhook.name,
+                notification.event.as_str(),
+                notification.stream.video_id
+            );
+            return Ok(());
+        }
+        let payload = notification.payload(self.webhook.format);
+        let mut delay = self.retry_delay;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = post(&self.webhook.url, &payload).await;
+            self.record_attempt(notification, attempt, &result).await?;
+            match result {
+                Ok(_) => return Ok(()),
+                Err((_, err)) if attempt < MAX_ATTEMPTS => {
+                    warn!(
+                        "Webhook {} attempt {attempt} failed, retrying in {delay:?}: {err:#}",
+                        self.webhook.name
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err((_, err)) => return Err(err.context("Giving up")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the webhook got the event of the notification's video.
+    async fn delivered(&self, notification: &Notification, event: Event) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "select 1 from webhook_deliveries \
+             where webhook = ? and video_id = ? and event = ? and delivered limit 1;",
+        )
+        .bind(&self.webhook.name)
+        .bind(&notification.stream.video_id)
+        .bind(event.as_str())
+        .fetch_optional(&self.pg)
+        .await
+        .context("Could not select deliveries")?;
+        Ok(row.is_some())
+    }
+
+    async fn record_attempt(
+        &self,
+        notification: &Notification,
+        attempt: u32,
+        result: &Result<u16, (Option<u16>, anyhow::Error)>,
+    ) -> anyhow::Result<()> {
+        let (status, error) = match result {
+            Ok(status) => (Some(*status), None),
+            Err((status, err)) => (*status, Some(format!("{err:#}"))),
+        };
+        sqlx::query(
+            "insert into webhook_deliveries \
+                 (time, webhook, video_id, event, attempt, status, error, delivered) \
+             values (?, ?, ?, ?, ?, ?, ?, ?);",
+        )
+        .bind(Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .bind(&self.webhook.name)
+        .bind(&notification.stream.video_id)
+        .bind(notification.event.as_str())
+        .bind(attempt)
+        .bind(status)
+        .bind(error)
+        .bind(result.is_ok())
+        .execute(&self.pg)
+        .await
+        .context("Could not record delivery")?;
+        Ok(())
+    }
+}
+
+/// Response status on success, along with the error otherwise.
+async fn post(url: &str, payload: &Value) -> Result<u16, (Option<u16>, anyhow::Error)> {
+    let res = awc::Client::default()
+        .post(url)
+        .send_json(payload)
+        .await
+        .map_err(|err| (None, anyhow!("Could not send webhook: {}", err)))?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err((
+            Some(status.as_u16()),
+            anyhow!("Webhook responded with {status}"),
+        ));
+    }
+    Ok(status.as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::streams::{self, SeenStream};
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Default)]
+    struct Receiver {
+        received: Mutex<Vec<(String, Value)>>,
+        flaky_calls: AtomicU32,
+    }
+
+    impl Receiver {
+        fn received(&self) -> Vec<(String, Value)> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    /// `/flaky` fails the first request.
+    async fn receive(
+        path: web::Path<String>,
+        body: web::Json<Value>,
+        receiver: web::Data<Receiver>,
+    ) -> HttpResponse {
+        if *path == "flaky" && receiver.flaky_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        receiver
+            .received
+            .lock()
+            .unwrap()
+            .push((path.into_inner(), body.into_inner()));
+        HttpResponse::NoContent().finish()
+    }
+
+    async fn wait_for(receiver: &Receiver, count: usize) -> Vec<(String, Value)> {
+        for _ in 0..100 {
+            let received = receiver.received();
+            if received.len() >= count {
+                return received;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!(
+            "Got {:?}, expected {count} notifications",
+            receiver.received()
+        );
+    }
+
+    /// Deliveries are recorded after the receiver got them.
+    async fn wait_for_deliveries(pg: &Pool<Sqlite>, count: i64) {
+        for _ in 0..100 {
+            let delivered: i64 = sqlx::query(
+                "select count(*) as delivered from webhook_deliveries where delivered;",
+            )
+            .fetch_one(pg)
+            .await
+            .unwrap()
+            .get("delivered");
+            if delivered >= count {
+                return;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Expected {count} deliveries");
+    }
+
+    async fn start_receiver() -> (Pool<Sqlite>, SocketAddr, web::Data<Receiver>) {
+        let pg = db::test_pool().await;
+        let receiver = web::Data::new(Receiver::default());
+        let server = {
+            let receiver = web::Data::clone(&receiver);
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::clone(&receiver))
+                    .route("/{path}", web::post().to(receive))
+            })
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap()
+        };
+        let addr = server.addrs()[0];
+        spawn(server.run());
+        (pg, addr, receiver)
+    }
+
+    #[actix_web::test]
+    pub async fn test_notify_webhooks() {
+        let (pg, addr, receiver) = start_receiver().await;
+        let webhooks = vec![
+            WebhookConfig {
+                name: String::from("discord"),
+                url: format!("http://{addr}/discord"),
+                format: WebhookFormat::Discord,
+            },
+            WebhookConfig {
+                name: String::from("generic"),
+                url: format!("http://{addr}/flaky"),
+                format: WebhookFormat::Generic,
+            },
+        ];
+        let notifier = Arc::new(Notifier::new(
+            Pool::clone(&pg),
+            webhooks.clone(),
+            Duration::from_millis(10),
+        ));
+        let channel = ChannelConfig {
+            id: String::from("UCL1s7OtDPaX3SdhW5433PRw"),
+            name: String::from("Buzkaa"),
+            slug: String::from("Buzkaa"),
+        };
+        let stream = LiveStream {
+            video_id: String::from("bKqZ4nQ2x7w"),
+            title: String::from("Kopiemy kamienie z widzami!"),
+            started_at: Utc::now(),
+            thumbnail_url: None,
+            viewers: Some(214),
+        };
+
+        notifier.observe(&channel, None).await;
+        notifier.observe(&channel, Some(stream.clone())).await;
+        notifier.observe(&channel, Some(stream.clone())).await;
+        let mut received = wait_for(&receiver, 2).await;
+        received.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let (_, discord) = &received[0];
+        assert_eq!(discord["embeds"][0]["title"], "Kopiemy kamienie z widzami!");
+        assert_eq!(
+            discord["embeds"][0]["url"],
+            "https://www.youtube.com/watch?v=bKqZ4nQ2x7w"
+        );
+        let (path, generic) = &received[1];
+        assert_eq!(path, "flaky");
+        assert_eq!(generic["event"], "live");
+        assert_eq!(generic["channel"]["slug"], "Buzkaa");
+        assert_eq!(generic["stream"]["viewers"], 214);
+        wait_for_deliveries(&pg, 2).await;
+        let attempts: i64 = sqlx::query(
+            "select count(*) as attempts from webhook_deliveries where webhook = 'generic';",
+        )
+        .fetch_one(&pg)
+        .await
+        .unwrap()
+        .get("attempts");
+        assert_eq!(attempts, 2);
+
+        // a restart forgets the live streams, but not the deliveries
+        let notifier = Arc::new(Notifier::new(
+            Pool::clone(&pg),
+            webhooks,
+            Duration::from_millis(10),
+        ));
+        notifier.observe(&channel, Some(stream.clone())).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receiver.received().len(), 2);
+
+        notifier.observe(&channel, None).await;
+        let received = wait_for(&receiver, 4).await;
+        assert!(received[2..]
+            .iter()
+            .any(|(path, body)| path == "flaky" && body["event"] == "offline"));
+    }
+
+    #[actix_web::test]
+    pub async fn test_notify_transitions() {
+        let (pg, addr, receiver) = start_receiver().await;
+        let webhooks = vec![WebhookConfig {
+            name: String::from("generic"),
+            url: format!("http://{addr}/generic"),
+            format: WebhookFormat::Generic,
+        }];
+        let notifier = Notifier::new(Pool::clone(&pg), webhooks, Duration::from_millis(10));
+        let channel = ChannelConfig {
+            id: String::from("UCL1s7OtDPaX3SdhW5433PRw"),
+            name: String::from("Buzkaa"),
+            slug: String::from("Buzkaa"),
+        };
+        let stream = |video_id: &str| LiveStream {
+            video_id: String::from(video_id),
+            title: String::from("Kopiemy kamienie z widzami!"),
+            started_at: Utc::now(),
+            thumbnail_url: None,
+            viewers: None,
+        };
+
+        // the stream was wrongly seen offline for a moment, and later switched to another one
+        notifier
+            .observe(&channel, Some(stream("bKqZ4nQ2x7w")))
+            .await;
+        notifier.observe(&channel, None).await;
+        notifier
+            .observe(&channel, Some(stream("bKqZ4nQ2x7w")))
+            .await;
+        notifier.observe(&channel, None).await;
+        notifier
+            .observe(&channel, Some(stream("bKqZ4nQ2x7w")))
+            .await;
+        notifier
+            .observe(&channel, Some(stream("Tn8vR2kEo1M")))
+            .await;
+        let received = wait_for(&receiver, 3).await;
+        let events = received
+            .iter()
+            .map(|(_, body)| {
+                (
+                    body["event"].as_str().unwrap(),
+                    body["stream"]["videoId"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                ("live", "bKqZ4nQ2x7w"),
+                ("offline", "bKqZ4nQ2x7w"),
+                ("live", "Tn8vR2kEo1M"),
+            ]
+        );
+        wait_for_deliveries(&pg, 3).await;
+
+        // a restart after the switch doesn't repeat anything
+        let webhooks = vec![WebhookConfig {
+            name: String::from("generic"),
+            url: format!("http://{addr}/generic"),
+            format: WebhookFormat::Generic,
+        }];
+        let notifier = Notifier::new(Pool::clone(&pg), webhooks, Duration::from_millis(10));
+        notifier
+            .observe(&channel, Some(stream("Tn8vR2kEo1M")))
+            .await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receiver.received().len(), 3);
+    }
+
+    #[actix_web::test]
+    pub async fn test_notify_ended_while_down() {
+        let (pg, addr, receiver) = start_receiver().await;
+        let webhooks = vec![WebhookConfig {
+            name: String::from("generic"),
+            url: format!("http://{addr}/generic"),
+            format: WebhookFormat::Generic,
+        }];
+        let notifier = Notifier::new(
+            Pool::clone(&pg),
+            webhooks.clone(),
+            Duration::from_millis(10),
+        );
+        let channel = ChannelConfig {
+            id: String::from("UCL1s7OtDPaX3SdhW5433PRw"),
+            name: String::from("Buzkaa"),
+            slug: String::from("Buzkaa"),
+        };
+        let stream = LiveStream {
+            video_id: String::from("bKqZ4nQ2x7w"),
+            title: String::from("Kopiemy kamienie z widzami!"),
+            started_at: Utc::now(),
+            thumbnail_url: None,
+            viewers: None,
+        };
+        let seen = SeenStream {
+            video_id: &stream.video_id,
+            channel_id: &channel.id,
+            title: &stream.title,
+            started_at: stream.started_at,
+            viewers: None,
+        };
+        streams::record_stream(&pg, &seen).await.unwrap();
+        notifier.observe(&channel, Some(stream.clone())).await;
+        wait_for_deliveries(&pg, 1).await;
+
+        // the stream ends while the server is down, it's first seen offline after the restart
+        let notifier = Notifier::new(Pool::clone(&pg), webhooks, Duration::from_millis(10));
+        notifier.observe(&channel, None).await;
+        let received = wait_for(&receiver, 2).await;
+        let (_, offline) = &received[1];
+        assert_eq!(offline["event"], "offline");
+        assert_eq!(offline["stream"]["videoId"], "bKqZ4nQ2x7w");
+        assert_eq!(offline["stream"]["title"], "Kopiemy kamienie z widzami!");
+        wait_for_deliveries(&pg, 2).await;
+
+        notifier.observe(&channel, None).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receiver.received().len(), 2);
+    }
+}
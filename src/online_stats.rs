@@ -0,0 +1,217 @@
+use crate::cache::MemoizedMap;
+use crate::online_users::Bucket;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Europe::Warsaw;
+use chrono_tz::Tz;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::time::Duration;
+
+/// How far back the weekday/hour averages look, so they follow the current trend.
+const WEEKDAY_HOURS_WEEKS: u64 = 12;
+
+#[derive(Deserialize)]
+pub struct OnlineStatsQuery {
+    /// Time zone of "today", "this week" and the weekday/hour grid, defaults to Europe/Warsaw.
+    tz: Option<Tz>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OnlineStats {
+    all_time: Option<Peak>,
+    today: Option<Peak>,
+    this_week: Option<Peak>,
+    /// Average online users per weekday (monday first) and hour over the last 12 weeks,
+    /// `null` where there are no samples.
+    weekday_hours: Vec<Vec<Option<f64>>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Peak {
+    count: u32,
+    /// First time the count was reached.
+    time: DateTime<Utc>,
+}
+
+pub async fn get_online_stats(pg: &Pool<Sqlite>, tz: Tz) -> anyhow::Result<OnlineStats> {
+    let today = Utc::now().with_timezone(&tz).date_naive();
+    let week_start = today - Days::new(today.weekday().num_days_from_monday().into());
+    Ok(OnlineStats {
+        all_time: select_peak(pg, None).await?,
+        today: select_peak(pg, Some(start_of_day(today, tz))).await?,
+        this_week: select_peak(pg, Some(start_of_day(week_start, tz))).await?,
+        weekday_hours: select_weekday_hours(
+            pg,
+            start_of_day(week_start - Days::new(7 * WEEKDAY_HOURS_WEEKS), tz),
+            tz,
+        )
+        .await?,
+    })
+}
+
+/// Start of the day in `tz`, as UTC.
+fn start_of_day(day: NaiveDate, tz: Tz) -> NaiveDateTime {
+    tz.from_local_datetime(&day.and_time(Default::default()))
+        .earliest()
+        .map_or_else(
+            || day.and_time(Default::default()),
+            |start| start.naive_utc(),
+        )
+}
+
+async fn select_peak(
+    pg: &Pool<Sqlite>,
+    since: Option<NaiveDateTime>,
+) -> anyhow::Result<Option<Peak>> {
+    let row = sqlx::query(&format!(
+        "with samples as ({}) \
+         select max, peak_time from samples where peak_time >= ? \
+         order by max desc, peak_time limit 1;",
+        Bucket::Hour.samples_sql()
+    ))
+    .bind(since.map_or_else(String::new, |since| since.to_string()))
+    .fetch_optional(pg)
+    .await
+    .context("Could not select peak")?;
+    row.map(|row| {
+        Ok(Peak {
+            count: row.get::<i64, _>("max") as u32,
+            time: NaiveDateTime::parse_from_str(row.get("peak_time"), "%Y-%m-%d %H:%M:%S")
+                .context("Invalid peak time")?
+                .and_utc(),
+        })
+    })
+    .transpose()
+}
+
+async fn select_weekday_hours(
+    pg: &Pool<Sqlite>,
+    since: NaiveDateTime,
+    tz: Tz,
+) -> anyhow::Result<Vec<Vec<Option<f64>>>> {
+    let rows = sqlx::query(&format!(
+        "with samples as ({}) \
+         select strftime('%Y-%m-%d %H:00:00', time) as period, sum(total) as total, \
+             sum(samples) as samples \
+         from samples where time >= ? group by period;",
+        Bucket::Hour.samples_sql()
+    ))
+    .bind(since.to_string())
+    .fetch_all(pg)
+    .await
+    .context("Could not select hourly samples")?;
+    let mut sums = [[(0.0, 0); 24]; 7];
+    for row in rows {
+        let period = NaiveDateTime::parse_from_str(row.get("period"), "%Y-%m-%d %H:%M:%S")
+            .context("Invalid period")?
+            .and_utc()
+            .with_timezone(&tz);
+        let (total, samples) =
+            &mut sums[period.weekday().num_days_from_monday() as usize][period.hour() as usize];
+        *total += row.get::<f64, _>("total");
+        *samples += row.get::<i64, _>("samples");
+    }
+    Ok(sums
+        .iter()
+        .map(|hours| {
+            hours
+                .iter()
+                .map(|&(total, samples)| {
+                    (samples > 0).then(|| (total / samples as f64 * 10.0).round() / 10.0)
+                })
+                .collect()
+        })
+        .collect())
+}
+
+#[derive(Clone)]
+pub struct OnlineStatsJson(String);
+
+impl OnlineStatsJson {
+    pub fn memoized_by_tz(pg: Pool<Sqlite>) -> MemoizedMap<Tz, Self> {
+        MemoizedMap::builder(Duration::from_secs(60), 16).build(move |tz| {
+            let pg = Pool::clone(&pg);
+            async move {
+                debug!("Generating new online stats json for {tz}...");
+                let stats = get_online_stats(&pg, tz).await?;
+                let json =
+                    serde_json::to_string(&stats).context("Could not serialize online stats")?;
+                Ok(Self(json))
+            }
+        })
+    }
+}
+
+#[get("/online-stats")]
+pub async fn get_stats(
+    query: web::Query<OnlineStatsQuery>,
+    stats: web::Data<MemoizedMap<Tz, OnlineStatsJson>>,
+) -> actix_web::Result<impl Responder> {
+    let stats = stats
+        .get(query.tz.unwrap_or(Warsaw))
+        .await
+        .inspect_err(|err| error!("Could not get online stats: {err:#}"))
+        .map_err(|_| actix_web::error::ErrorInternalServerError("no data"))?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(stats.age_header())
+        .body(stats.value.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::online_users;
+    use chrono::TimeDelta;
+
+    #[actix_web::test]
+    pub async fn test_online_stats() {
+        let pg = db::test_pool().await;
+        let now = Utc::now().naive_utc().with_nanosecond(0).unwrap();
+        let old_record = (now - TimeDelta::days(30)).with_minute(0).unwrap();
+        for (time, count) in [
+            (old_record, 50),
+            (old_record + TimeDelta::minutes(1), 40),
+            (now - TimeDelta::days(20), 50),
+            (now, 7),
+        ] {
+            sqlx::query("insert into online_users (time, count) values (?, ?);")
+                .bind(time.to_string())
+                .bind(count)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+        online_users::roll_up(&pg, TimeDelta::days(1))
+            .await
+            .unwrap();
+
+        let stats = get_online_stats(&pg, Tz::UTC).await.unwrap();
+        assert_eq!(
+            stats.all_time,
+            Some(Peak {
+                count: 50,
+                time: old_record.and_utc()
+            })
+        );
+        assert_eq!(
+            stats.today,
+            Some(Peak {
+                count: 7,
+                time: now.and_utc()
+            })
+        );
+        assert_eq!(stats.this_week, stats.today);
+        assert_eq!(stats.weekday_hours.len(), 7);
+        let old_hours = &stats.weekday_hours[old_record.weekday().num_days_from_monday() as usize];
+        assert_eq!(old_hours[old_record.hour() as usize], Some(45.0));
+        let today_hours = &stats.weekday_hours[now.weekday().num_days_from_monday() as usize];
+        assert_eq!(today_hours[now.hour() as usize], Some(7.0));
+    }
+}
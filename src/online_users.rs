@@ -1,24 +1,57 @@
+use crate::date_range::DateRange;
+use crate::online_versions;
 use actix_web::rt::time;
 use actix_web::web;
-use anyhow::Context;
-use chrono::NaiveDateTime;
+use anyhow::{bail, Context};
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, TimeDelta, Utc};
 use chrono_tz::Europe::Warsaw;
-use log::{error, info};
+use chrono_tz::Tz;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Row, Sqlite};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 pub type OnlineUsersData = web::Data<Mutex<OnlineUsers>>;
 
+/// How long a client counts as online after it was last seen.
+const PRESENCE_TTL: Duration = Duration::from_secs(70);
+/// Install ids are generated by the clients, so one ip can't keep inflating the count.
+pub const MAX_INSTALLS_PER_IP: usize = 32;
+
 pub struct OnlineUsers {
-    users: HashMap<String, Instant>,
+    /// Clients sending heartbeats, by install id.
+    installs: HashMap<String, Presence>,
+    /// Old clients only seen polling `/youtube/Buzkaa`, by ip.
+    users: HashMap<String, Visit>,
     last_cleanup_at: Instant,
 }
 
+struct Visit {
+    seen_at: Instant,
+    /// From the `X-Client-Version` header, clients sending heartbeats always report it.
+    version: Option<u32>,
+}
+
+struct Presence {
+    seen_at: Instant,
+    ip: String,
+    version: u32,
+    session: Option<String>,
+}
+
+pub struct Heartbeat {
+    pub install_id: String,
+    pub ip: String,
+    pub version: u32,
+    pub session: Option<String>,
+}
+
 impl OnlineUsers {
     pub fn new() -> Self {
         Self {
+            installs: Default::default(),
             users: Default::default(),
             last_cleanup_at: Instant::now(),
         }
@@ -26,28 +59,119 @@ impl OnlineUsers {
 
     fn cleanup(&mut self) {
         self.last_cleanup_at = Instant::now();
+        self.installs
+            .retain(|_, presence| presence.seen_at.elapsed() < PRESENCE_TTL);
         self.users
-            .retain(|_, visited_at| visited_at.elapsed().as_secs() < 70);
+            .retain(|_, visit| visit.seen_at.elapsed() < PRESENCE_TTL);
     }
 
-    pub fn count(&mut self) -> u32 {
+    fn cleanup_if_due(&mut self) {
         if self.last_cleanup_at.elapsed().as_secs() >= 10 {
             self.cleanup()
         }
-        self.users.len() as u32
     }
 
-    pub fn keep_alive(&mut self, ip: String) {
-        self.users.insert(ip, Instant::now());
+    /// Old clients on ips no install is seen from, so a new client polling the live endpoint
+    /// isn't counted twice.
+    fn old_clients(&self) -> impl Iterator<Item = &Visit> {
+        let install_ips: HashSet<&str> = self
+            .installs
+            .values()
+            .map(|presence| presence.ip.as_str())
+            .collect();
+        self.users
+            .iter()
+            .filter(move |(ip, _)| !install_ips.contains(ip.as_str()))
+            .map(|(_, visit)| visit)
+    }
+
+    pub fn count(&mut self) -> u32 {
+        self.cleanup_if_due();
+        (self.installs.len() + self.old_clients().count()) as u32
+    }
+
+    /// Online users per client version, `None` for old clients not reporting one.
+    pub fn count_by_version(&mut self) -> BTreeMap<Option<u32>, u32> {
+        self.cleanup_if_due();
+        let mut counts = BTreeMap::new();
+        let versions = self
+            .installs
+            .values()
+            .map(|presence| Some(presence.version))
+            .chain(self.old_clients().map(|visit| visit.version));
+        for version in versions {
+            *counts.entry(version).or_default() += 1;
+        }
+        counts
+    }
+
+    pub fn keep_alive(&mut self, ip: String, version: Option<u32>) {
+        let visit = Visit {
+            seen_at: Instant::now(),
+            version,
+        };
+        self.users.insert(ip, visit);
+    }
+
+    /// Returns `false` if the install is new and its ip already has [`MAX_INSTALLS_PER_IP`]
+    /// installs online.
+    pub fn heartbeat(&mut self, heartbeat: Heartbeat) -> bool {
+        if !self.installs.contains_key(&heartbeat.install_id) {
+            let installs_on_ip = self
+                .installs
+                .values()
+                .filter(|presence| {
+                    presence.ip == heartbeat.ip && presence.seen_at.elapsed() < PRESENCE_TTL
+                })
+                .count();
+            if installs_on_ip >= MAX_INSTALLS_PER_IP {
+                return false;
+            }
+        }
+        let presence = Presence {
+            seen_at: Instant::now(),
+            ip: heartbeat.ip,
+            version: heartbeat.version,
+            session: heartbeat.session,
+        };
+        let new_session = self
+            .installs
+            .get(&heartbeat.install_id)
+            .is_none_or(|previous| previous.session != presence.session);
+        if new_session {
+            debug!(
+                "Install {} started session {:?} on v{}.",
+                heartbeat.install_id, presence.session, presence.version
+            );
+        }
+        self.installs.insert(heartbeat.install_id, presence);
+        true
     }
 }
 
-async fn insert_online_users(pg: &Pool<Sqlite>, online_count: u32) -> anyhow::Result<()> {
-    sqlx::query("insert into online_users (time, count) values (datetime('now'), ?);")
+async fn insert_online_users(
+    pg: &Pool<Sqlite>,
+    online_count: u32,
+    versions: &BTreeMap<Option<u32>, u32>,
+) -> anyhow::Result<()> {
+    let now = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let mut tx = pg.begin().await.context("Could not begin transaction")?;
+    sqlx::query("insert into online_users (time, count) values (?, ?);")
+        .bind(&now)
         .bind(online_count as i32)
-        .execute(pg)
+        .execute(&mut *tx)
         .await
         .context("Could not insert record")?;
+    for (version, count) in versions {
+        sqlx::query("insert into online_versions (time, version, count) values (?, ?, ?);")
+            .bind(&now)
+            .bind(version)
+            .bind(count)
+            .execute(&mut *tx)
+            .await
+            .context("Could not insert version record")?;
+    }
+    tx.commit().await.context("Could not commit records")?;
     Ok(())
 }
 
@@ -56,11 +180,11 @@ pub async fn start_archiving(pg: Pool<Sqlite>, online_users_data: OnlineUsersDat
     store_interval.tick().await;
     loop {
         store_interval.tick().await;
-        let online_count = online_users_data
-            .lock()
-            .expect("Online users poisoned!")
-            .count();
-        match insert_online_users(&pg, online_count).await {
+        let (online_count, versions) = {
+            let mut online_users = online_users_data.lock().expect("Online users poisoned!");
+            (online_users.count(), online_users.count_by_version())
+        };
+        match insert_online_users(&pg, online_count, &versions).await {
             Ok(_) => {
                 info!("Archived online users (count: {online_count}).");
             }
@@ -71,6 +195,229 @@ pub async fn start_archiving(pg: Pool<Sqlite>, online_users_data: OnlineUsersDat
     }
 }
 
+/// Presence as saved in the `presence` table. `Instant`s can't outlive the process, so it keeps
+/// the wall clock time the client was last seen at.
+struct SavedPresence {
+    install_id: Option<String>,
+    ip: String,
+    version: Option<u32>,
+    session: Option<String>,
+    seen_at: NaiveDateTime,
+}
+
+impl OnlineUsers {
+    fn snapshot(&mut self) -> Vec<SavedPresence> {
+        self.cleanup();
+        let now = Utc::now().naive_utc();
+        let seen_at = |instant: Instant| {
+            now - TimeDelta::from_std(instant.elapsed()).unwrap_or(TimeDelta::zero())
+        };
+        let installs = self
+            .installs
+            .iter()
+            .map(|(install_id, presence)| SavedPresence {
+                install_id: Some(install_id.clone()),
+                ip: presence.ip.clone(),
+                version: Some(presence.version),
+                session: presence.session.clone(),
+                seen_at: seen_at(presence.seen_at),
+            });
+        let users = self.users.iter().map(|(ip, visit)| SavedPresence {
+            install_id: None,
+            ip: ip.clone(),
+            version: visit.version,
+            session: None,
+            seen_at: seen_at(visit.seen_at),
+        });
+        installs.chain(users).collect()
+    }
+
+    fn restore(saved: Vec<SavedPresence>) -> Self {
+        let mut online_users = Self::new();
+        let now = Utc::now().naive_utc();
+        for presence in saved {
+            // clock going backwards counts as just seen
+            let age = (now - presence.seen_at).to_std().unwrap_or_default();
+            if age >= PRESENCE_TTL {
+                continue;
+            }
+            let seen_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+            match (presence.install_id, presence.version) {
+                (Some(install_id), Some(version)) => {
+                    let presence = Presence {
+                        seen_at,
+                        ip: presence.ip,
+                        version,
+                        session: presence.session,
+                    };
+                    online_users.installs.insert(install_id, presence);
+                }
+                (Some(_), None) => {}
+                (None, version) => {
+                    let visit = Visit { seen_at, version };
+                    online_users.users.insert(presence.ip, visit);
+                }
+            }
+        }
+        online_users
+    }
+}
+
+/// Restores the presence saved before the last shutdown, without the clients that went offline
+/// in the meantime.
+pub async fn load_presence(pg: &Pool<Sqlite>) -> anyhow::Result<OnlineUsers> {
+    let rows = sqlx::query("select install_id, ip, version, session, seen_at from presence;")
+        .fetch_all(pg)
+        .await
+        .context("Could not select presence")?;
+    let saved = rows
+        .into_iter()
+        .map(|row| {
+            Ok(SavedPresence {
+                install_id: row.get("install_id"),
+                ip: row.get("ip"),
+                version: row.get::<Option<i64>, _>("version").map(|v| v as u32),
+                session: row.get("session"),
+                seen_at: NaiveDateTime::parse_from_str(row.get("seen_at"), "%Y-%m-%d %H:%M:%S")
+                    .context("Invalid seen at")?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(OnlineUsers::restore(saved))
+}
+
+/// Replaces the saved presence with the current one, so a restart doesn't look like everyone
+/// went offline.
+pub async fn save_presence(
+    pg: &Pool<Sqlite>,
+    online_users_data: &OnlineUsersData,
+) -> anyhow::Result<usize> {
+    let saved = online_users_data
+        .lock()
+        .expect("Online users poisoned!")
+        .snapshot();
+    let mut tx = pg.begin().await.context("Could not begin transaction")?;
+    sqlx::query("delete from presence;")
+        .execute(&mut *tx)
+        .await
+        .context("Could not delete old presence")?;
+    for presence in &saved {
+        sqlx::query(
+            "insert into presence (install_id, ip, version, session, seen_at) \
+             values (?, ?, ?, ?, ?);",
+        )
+        .bind(&presence.install_id)
+        .bind(&presence.ip)
+        .bind(presence.version)
+        .bind(&presence.session)
+        .bind(presence.seen_at.format("%Y-%m-%d %H:%M:%S").to_string())
+        .execute(&mut *tx)
+        .await
+        .context("Could not insert presence")?;
+    }
+    tx.commit().await.context("Could not commit presence")?;
+    Ok(saved.len())
+}
+
+/// Saves the presence every `every`, in case the process doesn't get to save it on shutdown.
+pub async fn start_saving_presence(
+    pg: Pool<Sqlite>,
+    online_users_data: OnlineUsersData,
+    every: Duration,
+) {
+    let mut save_interval = time::interval(every);
+    save_interval.tick().await;
+    loop {
+        save_interval.tick().await;
+        match save_presence(&pg, &online_users_data).await {
+            Ok(count) => debug!("Saved presence of {count} clients."),
+            Err(err) => error!("Could not save presence: {err:#}."),
+        }
+    }
+}
+
+/// Rolls complete hours up into `online_users_hourly`, redoing the last one rolled up.
+const HOURLY_ROLLUP_SQL: &str = "\
+    with samples as ( \
+        select strftime('%Y-%m-%d %H:00:00', time) as period, time, count, \
+            row_number() over ( \
+                partition by strftime('%Y-%m-%d %H:00:00', time) order by count desc, time \
+            ) as rank \
+        from online_users \
+        where count is not null and time < ? \
+            and time >= (select coalesce(max(time), '') from online_users_hourly) \
+    ) \
+    insert into online_users_hourly (time, min, avg, max, peak_time, samples) \
+    select period, min(count), avg(count), max(count), \
+        max(case when rank = 1 then time end), count(*) \
+    from samples where true group by period \
+    on conflict (time) do update set min = excluded.min, avg = excluded.avg, \
+        max = excluded.max, peak_time = excluded.peak_time, samples = excluded.samples;";
+
+/// Rolls complete days up into `online_users_daily` from the hourly rollups.
+const DAILY_ROLLUP_SQL: &str = "\
+    with hours as ( \
+        select date(time) || ' 00:00:00' as period, min, avg, max, peak_time, samples, \
+            row_number() over (partition by date(time) order by max desc, peak_time) as rank \
+        from online_users_hourly \
+        where time < ? and time >= (select coalesce(max(time), '') from online_users_daily) \
+    ) \
+    insert into online_users_daily (time, min, avg, max, peak_time, samples) \
+    select period, min(min), sum(avg * samples) / sum(samples), max(max), \
+        max(case when rank = 1 then peak_time end), sum(samples) \
+    from hours where true group by period \
+    on conflict (time) do update set min = excluded.min, avg = excluded.avg, \
+        max = excluded.max, peak_time = excluded.peak_time, samples = excluded.samples;";
+
+/// Updates the hourly and daily rollups of the online users and versions, then deletes minute
+/// samples that are older than `minute_retention` and already rolled up.
+pub async fn roll_up(pg: &Pool<Sqlite>, minute_retention: TimeDelta) -> anyhow::Result<()> {
+    roll_up_at(pg, Utc::now().naive_utc(), minute_retention).await
+}
+
+/// [`roll_up`] as if it ran at `now`.
+async fn roll_up_at(
+    pg: &Pool<Sqlite>,
+    now: NaiveDateTime,
+    minute_retention: TimeDelta,
+) -> anyhow::Result<()> {
+    let mut tx = pg.begin().await.context("Could not begin transaction")?;
+    sqlx::query(HOURLY_ROLLUP_SQL)
+        .bind(now.format("%Y-%m-%d %H:00:00").to_string())
+        .execute(&mut *tx)
+        .await
+        .context("Could not roll up hours")?;
+    sqlx::query(DAILY_ROLLUP_SQL)
+        .bind(now.format("%Y-%m-%d 00:00:00").to_string())
+        .execute(&mut *tx)
+        .await
+        .context("Could not roll up days")?;
+    let deleted = sqlx::query(
+        "delete from online_users where time < ? \
+         and time < (select datetime(max(time), '+1 hour') from online_users_hourly);",
+    )
+    .bind((now - minute_retention).to_string())
+    .execute(&mut *tx)
+    .await
+    .context("Could not delete old samples")?
+    .rows_affected();
+    online_versions::roll_up_at(&mut tx, now, minute_retention).await?;
+    tx.commit().await.context("Could not commit rollups")?;
+    info!("Rolled up online users (deleted {deleted} old samples).");
+    Ok(())
+}
+
+pub async fn start_rollups(pg: Pool<Sqlite>, minute_retention_days: u32, every: Duration) {
+    let mut rollup_interval = time::interval(every);
+    loop {
+        rollup_interval.tick().await;
+        let minute_retention = TimeDelta::days(minute_retention_days.into());
+        if let Err(err) = roll_up(&pg, minute_retention).await {
+            error!("Could not roll up online users: {err:#}.");
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 pub struct ChartData {
     #[serde(rename = "labels")]
@@ -102,3 +449,609 @@ pub async fn get_chart_data(pg: &Pool<Sqlite>) -> anyhow::Result<ChartData> {
         counts,
     })
 }
+
+/// Bucket the chart samples are aggregated into.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    /// Finest first, so a range without an explicit bucket gets the most detailed one it fits.
+    const ALL: [Bucket; 3] = [Bucket::Minute, Bucket::Hour, Bucket::Day];
+
+    /// SQLite expression truncating `time` to the start of the bucket.
+    pub fn period_sql(self) -> &'static str {
+        match self {
+            Bucket::Minute => "strftime('%Y-%m-%d %H:%M:00', time)",
+            Bucket::Hour => "strftime('%Y-%m-%d %H:00:00', time)",
+            Bucket::Day => "strftime('%Y-%m-%d 00:00:00', time)",
+        }
+    }
+
+    /// Keeps a response around 10k points at most.
+    pub fn max_range_days(self) -> u64 {
+        match self {
+            Bucket::Minute => 7,
+            Bucket::Hour => 366,
+            Bucket::Day => 20 * 366,
+        }
+    }
+
+    /// Tables read for the bucket, coarsest first. Each one covers the time the coarser ones
+    /// haven't rolled up yet.
+    fn tiers(self) -> &'static [Tier] {
+        match self {
+            Bucket::Minute => &[Tier::Minute],
+            Bucket::Hour => &[Tier::Hourly, Tier::Minute],
+            Bucket::Day => &[Tier::Daily, Tier::Hourly, Tier::Minute],
+        }
+    }
+
+    /// Union of the tiers read for the bucket, as `time, min, total, samples, max, peak_time`
+    /// rows where `total / samples` is the average.
+    pub fn samples_sql(self) -> String {
+        let mut parts = Vec::new();
+        let mut covered_until = None;
+        for tier in self.tiers() {
+            let mut part = tier.samples_sql().to_owned();
+            if let Some(covered_until) = covered_until {
+                part += &format!(" and time >= {covered_until}");
+            }
+            if let Some(complete_until) = tier.complete_until_sql() {
+                part += &format!(" and time < {complete_until}");
+                covered_until = Some(complete_until);
+            }
+            parts.push(part);
+        }
+        parts.join(" union all ")
+    }
+
+    fn chart_sql(self) -> String {
+        format!(
+            "with samples as ({}) \
+             select {} as period, min(min) as min, sum(total) / sum(samples) as avg, \
+                 max(max) as max \
+             from samples where time >= ? and time < ? \
+             group by period order by period desc;",
+            self.samples_sql(),
+            self.period_sql()
+        )
+    }
+
+    fn label_format(self) -> &'static str {
+        match self {
+            Bucket::Minute | Bucket::Hour => "%d.%m %H:%M",
+            Bucket::Day => "%d.%m.%Y",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Tier {
+    Daily,
+    Hourly,
+    Minute,
+}
+
+impl Tier {
+    /// Samples as partial aggregates, so rows of different tiers can be merged.
+    fn samples_sql(self) -> &'static str {
+        match self {
+            Tier::Daily => {
+                "select time, min, avg * samples as total, samples, max, peak_time \
+                 from online_users_daily where true"
+            }
+            Tier::Hourly => {
+                "select time, min, avg * samples as total, samples, max, peak_time \
+                 from online_users_hourly where true"
+            }
+            Tier::Minute => {
+                "select time, count as min, cast(count as real) as total, 1 as samples, \
+                     count as max, time as peak_time \
+                 from online_users where count is not null"
+            }
+        }
+    }
+
+    /// Time up to which the tier is rolled up, the minute samples are always complete.
+    fn complete_until_sql(self) -> Option<&'static str> {
+        match self {
+            Tier::Daily => {
+                Some("(select coalesce(datetime(max(time), '+1 day'), '') from online_users_daily)")
+            }
+            Tier::Hourly => Some(
+                "(select coalesce(datetime(max(time), '+1 hour'), '') from online_users_hourly)",
+            ),
+            Tier::Minute => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ChartQuery {
+    /// UTC day, defaults to a week before `to`.
+    pub from: Option<NaiveDate>,
+    /// Inclusive UTC day, defaults to today.
+    pub to: Option<NaiveDate>,
+    /// Defaults to the finest bucket allowed for the range.
+    pub bucket: Option<Bucket>,
+    #[serde(default)]
+    pub format: ChartFormat,
+    /// Time zone of the labels, defaults to Europe/Warsaw. Day buckets are always UTC days.
+    pub tz: Option<Tz>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ChartFormat {
+    /// Preformatted labels for the chart widget, see [`BucketedChartData`].
+    #[default]
+    Labels,
+    /// RFC 3339 UTC timestamps, see [`IsoChartData`].
+    Iso,
+}
+
+impl ChartQuery {
+    /// Query answered with the raw minute rows of the last week, the original chart.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Range of the query and the bucket size, the finest one fitting the range unless given.
+    /// Minute buckets are only available for ranges within the minute retention.
+    pub fn resolve(
+        &self,
+        minute_retention: MinuteRetention,
+    ) -> anyhow::Result<(DateRange, Bucket)> {
+        let range = DateRange::resolve(self.from, self.to, 7, Bucket::Day.max_range_days())?;
+        let minutes_kept = minute_retention.covers(range.from);
+        let bucket = match self.bucket {
+            Some(bucket) if range.days() > bucket.max_range_days() => bail!(
+                "{bucket:?} buckets allow ranges up to {} days",
+                bucket.max_range_days()
+            ),
+            Some(Bucket::Minute) if !minutes_kept => bail!(
+                "Minute buckets are only kept for the last {} days",
+                minute_retention.0
+            ),
+            Some(bucket) => bucket,
+            None => Bucket::ALL
+                .into_iter()
+                .filter(|bucket| *bucket != Bucket::Minute || minutes_kept)
+                .find(|bucket| range.days() <= bucket.max_range_days())
+                .context("range is too long")?,
+        };
+        Ok((range, bucket))
+    }
+}
+
+/// Days the per minute samples are kept for (`online_users.minute_retention_days`).
+#[derive(Copy, Clone, Debug)]
+pub struct MinuteRetention(pub u32);
+
+impl MinuteRetention {
+    /// Whether the minute samples from `day` on are all still kept.
+    fn covers(self, day: NaiveDate) -> bool {
+        Utc::now()
+            .date_naive()
+            .checked_sub_days(Days::new(self.0.into()))
+            .is_some_and(|oldest| day > oldest)
+    }
+}
+
+/// Same as [`ChartData`] with `data` holding the average of each bucket.
+#[derive(Serialize)]
+pub struct BucketedChartData {
+    bucket: Bucket,
+    labels: Vec<String>,
+    data: Vec<f64>,
+    min: Vec<u32>,
+    max: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct IsoChartData {
+    bucket: Bucket,
+    /// Newest first, like the labels.
+    points: Vec<IsoChartPoint>,
+}
+
+#[derive(Serialize)]
+struct IsoChartPoint {
+    /// Start of the bucket.
+    time: DateTime<Utc>,
+    avg: f64,
+    min: u32,
+    max: u32,
+}
+
+struct BucketRow {
+    start: NaiveDateTime,
+    avg: f64,
+    min: u32,
+    max: u32,
+}
+
+pub async fn get_bucketed_chart_data(
+    pg: &Pool<Sqlite>,
+    query: ChartQuery,
+    minute_retention: MinuteRetention,
+) -> anyhow::Result<BucketedChartData> {
+    let (bucket, rows) = select_buckets(pg, query, minute_retention).await?;
+    let tz = query.tz.unwrap_or(Warsaw);
+    let mut chart = BucketedChartData {
+        bucket,
+        labels: Vec::with_capacity(rows.len()),
+        data: Vec::with_capacity(rows.len()),
+        min: Vec::with_capacity(rows.len()),
+        max: Vec::with_capacity(rows.len()),
+    };
+    for row in rows {
+        let label = match bucket {
+            Bucket::Day => row.start.format(bucket.label_format()).to_string(),
+            _ => row
+                .start
+                .and_utc()
+                .with_timezone(&tz)
+                .format(bucket.label_format())
+                .to_string(),
+        };
+        chart.labels.push(label);
+        chart.data.push(row.avg);
+        chart.min.push(row.min);
+        chart.max.push(row.max);
+    }
+    Ok(chart)
+}
+
+pub async fn get_iso_chart_data(
+    pg: &Pool<Sqlite>,
+    query: ChartQuery,
+    minute_retention: MinuteRetention,
+) -> anyhow::Result<IsoChartData> {
+    let (bucket, rows) = select_buckets(pg, query, minute_retention).await?;
+    let points = rows
+        .into_iter()
+        .map(|row| IsoChartPoint {
+            time: row.start.and_utc(),
+            avg: row.avg,
+            min: row.min,
+            max: row.max,
+        })
+        .collect();
+    Ok(IsoChartData { bucket, points })
+}
+
+async fn select_buckets(
+    pg: &Pool<Sqlite>,
+    query: ChartQuery,
+    minute_retention: MinuteRetention,
+) -> anyhow::Result<(Bucket, Vec<BucketRow>)> {
+    let (range, bucket) = query.resolve(minute_retention)?;
+    let rows = sqlx::query(&bucket.chart_sql())
+        .bind(range.start_time())
+        .bind(range.end_time())
+        .fetch_all(pg)
+        .await
+        .context("Could not select data")?;
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            Ok(BucketRow {
+                start: NaiveDateTime::parse_from_str(row.get("period"), "%Y-%m-%d %H:%M:%S")
+                    .context("Invalid period")?,
+                // one decimal is plenty for a chart
+                avg: (row.get::<f64, _>("avg") * 10.0).round() / 10.0,
+                min: row.get::<i64, _>("min") as u32,
+                max: row.get::<i64, _>("max") as u32,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok((bucket, rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    const RETENTION: MinuteRetention = MinuteRetention(30);
+
+    #[test]
+    pub fn test_count_installs_and_ips() {
+        let mut online_users = OnlineUsers::new();
+        for install_id in ["install-a", "install-b"] {
+            online_users.heartbeat(Heartbeat {
+                install_id: String::from(install_id),
+                ip: String::from("1.1.1.1"),
+                version: 17,
+                session: None,
+            });
+        }
+        assert_eq!(online_users.count(), 2);
+        // same nat, one of the installs polling the live endpoint
+        online_users.keep_alive(String::from("1.1.1.1"), None);
+        assert_eq!(online_users.count(), 2);
+        online_users.keep_alive(String::from("2.2.2.2"), None);
+        assert_eq!(online_users.count(), 3);
+        online_users.heartbeat(Heartbeat {
+            install_id: String::from("install-a"),
+            ip: String::from("1.1.1.1"),
+            version: 17,
+            session: Some(String::from("session")),
+        });
+        assert_eq!(online_users.count(), 3);
+        online_users.heartbeat(Heartbeat {
+            install_id: String::from("install-c"),
+            ip: String::from("3.3.3.3"),
+            version: 16,
+            session: None,
+        });
+        online_users.keep_alive(String::from("4.4.4.4"), Some(16));
+        assert_eq!(
+            online_users.count_by_version(),
+            BTreeMap::from([(None, 1), (Some(16), 2), (Some(17), 2)])
+        );
+    }
+
+    #[test]
+    pub fn test_max_installs_per_ip() {
+        let mut online_users = OnlineUsers::new();
+        let heartbeat = |install_id: usize, ip: &str| Heartbeat {
+            install_id: format!("install-{install_id}"),
+            ip: String::from(ip),
+            version: 17,
+            session: None,
+        };
+        for install_id in 0..MAX_INSTALLS_PER_IP {
+            assert!(online_users.heartbeat(heartbeat(install_id, "1.1.1.1")));
+        }
+        assert!(!online_users.heartbeat(heartbeat(MAX_INSTALLS_PER_IP, "1.1.1.1")));
+        assert!(online_users.heartbeat(heartbeat(0, "1.1.1.1")));
+        assert!(online_users.heartbeat(heartbeat(MAX_INSTALLS_PER_IP, "2.2.2.2")));
+        assert_eq!(online_users.count(), MAX_INSTALLS_PER_IP as u32 + 1);
+    }
+
+    #[actix_web::test]
+    pub async fn test_save_presence() {
+        let pg = db::test_pool().await;
+        let online_users = web::Data::new(Mutex::new(OnlineUsers::new()));
+        {
+            let mut online_users = online_users.lock().unwrap();
+            online_users.heartbeat(Heartbeat {
+                install_id: String::from("install-a"),
+                ip: String::from("1.1.1.1"),
+                version: 17,
+                session: Some(String::from("session")),
+            });
+            online_users.keep_alive(String::from("2.2.2.2"), Some(16));
+        }
+        assert_eq!(save_presence(&pg, &online_users).await.unwrap(), 2);
+        // saved twice, replacing the first save
+        assert_eq!(save_presence(&pg, &online_users).await.unwrap(), 2);
+        let expired = (Utc::now() - TimeDelta::seconds(PRESENCE_TTL.as_secs() as i64 + 5))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        sqlx::query("insert into presence (ip, seen_at) values ('3.3.3.3', ?);")
+            .bind(expired)
+            .execute(&pg)
+            .await
+            .unwrap();
+
+        let mut restored = load_presence(&pg).await.unwrap();
+        assert_eq!(restored.count(), 2);
+        assert_eq!(
+            restored.count_by_version(),
+            BTreeMap::from([(Some(16), 1), (Some(17), 1)])
+        );
+        let install = &restored.installs["install-a"];
+        assert_eq!(install.ip, "1.1.1.1");
+        assert_eq!(install.session.as_deref(), Some("session"));
+        assert!(install.seen_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[actix_web::test]
+    pub async fn test_bucketed_chart() {
+        let pg = db::test_pool().await;
+        for (time, count) in [
+            ("2026-10-05 10:00:00", 4),
+            ("2026-10-05 10:01:00", 8),
+            ("2026-10-05 11:00:00", 3),
+            ("2026-10-06 10:00:00", 10),
+            ("2026-10-08 10:00:00", 100),
+        ] {
+            sqlx::query("insert into online_users (time, count) values (?, ?);")
+                .bind(time)
+                .bind(count)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+        let query = ChartQuery {
+            from: Some(NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()),
+            to: Some(NaiveDate::from_ymd_opt(2026, 10, 6).unwrap()),
+            bucket: Some(Bucket::Hour),
+            ..Default::default()
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.labels, ["06.10 12:00", "05.10 13:00", "05.10 12:00"]);
+        assert_eq!(chart.data, [10.0, 3.0, 6.0]);
+        assert_eq!(chart.min, [10, 3, 4]);
+        assert_eq!(chart.max, [10, 3, 8]);
+
+        let query = ChartQuery {
+            bucket: Some(Bucket::Day),
+            ..query
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.labels, ["06.10.2026", "05.10.2026"]);
+        assert_eq!(chart.data, [10.0, 5.0]);
+
+        let query = ChartQuery {
+            from: Some(NaiveDate::from_ymd_opt(2026, 1, 1).unwrap()),
+            bucket: None,
+            ..query
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.bucket, Bucket::Hour);
+        let query = ChartQuery {
+            bucket: Some(Bucket::Minute),
+            ..query
+        };
+        assert!(get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .is_err());
+        let query = ChartQuery {
+            from: None,
+            to: Some(NaiveDate::MAX),
+            ..query
+        };
+        assert!(get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .is_err());
+
+        let today = Utc::now().date_naive();
+        let query = ChartQuery {
+            from: Some(today - Days::new(10)),
+            to: Some(today - Days::new(9)),
+            bucket: Some(Bucket::Minute),
+            ..Default::default()
+        };
+        assert!(get_bucketed_chart_data(&pg, query, MinuteRetention(7))
+            .await
+            .is_err());
+        let query = ChartQuery {
+            bucket: None,
+            ..query
+        };
+        let chart = get_bucketed_chart_data(&pg, query, MinuteRetention(7))
+            .await
+            .unwrap();
+        assert_eq!(chart.bucket, Bucket::Hour);
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.bucket, Bucket::Minute);
+
+        let query = ChartQuery {
+            to: Some(NaiveDate::from_ymd_opt(2026, 10, 6).unwrap()),
+            bucket: Some(Bucket::Hour),
+            tz: Some(Tz::UTC),
+            ..Default::default()
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.labels, ["06.10 10:00", "05.10 11:00", "05.10 10:00"]);
+        let query = ChartQuery {
+            format: ChartFormat::Iso,
+            ..query
+        };
+        let chart =
+            serde_json::to_value(get_iso_chart_data(&pg, query, RETENTION).await.unwrap()).unwrap();
+        assert_eq!(
+            chart["points"][2],
+            serde_json::json!({"time": "2026-10-05T10:00:00Z", "avg": 6.0, "min": 4, "max": 8})
+        );
+    }
+
+    #[actix_web::test]
+    pub async fn test_roll_up() {
+        let pg = db::test_pool().await;
+        let now = "2026-10-08T12:30:00".parse::<NaiveDateTime>().unwrap();
+        let today = now.date();
+        let first_day = today - Days::new(3);
+        let second_day = today - Days::new(2);
+        let at = |day: NaiveDate, time: &str| format!("{day} {time}");
+        for (time, count) in [
+            (at(first_day, "10:00:00"), 4),
+            (at(first_day, "10:01:00"), 8),
+            (at(first_day, "11:00:00"), 3),
+            (at(second_day, "10:00:00"), 10),
+            (at(today, "12:29:00"), 1),
+        ] {
+            sqlx::query("insert into online_users (time, count) values (?, ?);")
+                .bind(time)
+                .bind(count)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            roll_up_at(&pg, now, TimeDelta::days(1)).await.unwrap();
+            let hours = sqlx::query(
+                "select time, min, avg, max, peak_time, samples \
+                 from online_users_hourly order by time;",
+            )
+            .fetch_all(&pg)
+            .await
+            .unwrap();
+            assert_eq!(hours.len(), 3);
+            assert_eq!(hours[0].get::<String, _>("time"), at(first_day, "10:00:00"));
+            assert_eq!(hours[0].get::<i64, _>("min"), 4);
+            assert_eq!(hours[0].get::<f64, _>("avg"), 6.0);
+            assert_eq!(hours[0].get::<i64, _>("max"), 8);
+            assert_eq!(
+                hours[0].get::<String, _>("peak_time"),
+                at(first_day, "10:01:00")
+            );
+            assert_eq!(hours[0].get::<i64, _>("samples"), 2);
+
+            let days = sqlx::query(
+                "select time, min, avg, max, peak_time, samples \
+                 from online_users_daily order by time;",
+            )
+            .fetch_all(&pg)
+            .await
+            .unwrap();
+            assert_eq!(days.len(), 2);
+            assert_eq!(days[0].get::<i64, _>("min"), 3);
+            assert_eq!(days[0].get::<f64, _>("avg"), 5.0);
+            assert_eq!(days[0].get::<i64, _>("max"), 8);
+            assert_eq!(days[0].get::<i64, _>("samples"), 3);
+            assert_eq!(
+                days[1].get::<String, _>("peak_time"),
+                at(second_day, "10:00:00")
+            );
+
+            let minutes: i64 = sqlx::query("select count(*) from online_users;")
+                .fetch_one(&pg)
+                .await
+                .unwrap()
+                .get(0);
+            assert_eq!(minutes, 1);
+        }
+
+        let query = ChartQuery {
+            from: Some(first_day),
+            to: Some(today),
+            bucket: Some(Bucket::Day),
+            ..Default::default()
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.data, [1.0, 10.0, 5.0]);
+        assert_eq!(chart.min, [1, 10, 3]);
+        let query = ChartQuery {
+            bucket: Some(Bucket::Hour),
+            ..query
+        };
+        let chart = get_bucketed_chart_data(&pg, query, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(chart.data, [1.0, 10.0, 3.0, 6.0]);
+        assert_eq!(chart.max, [1, 10, 3, 8]);
+    }
+}
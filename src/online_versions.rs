@@ -0,0 +1,445 @@
+use crate::cache::MemoizedMap;
+use crate::date_range::{DateRange, MAX_CACHED_QUERIES};
+use crate::online_users::{Bucket, OnlineUsersData};
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeDelta, Utc};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite, SqliteConnection};
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct VersionCount {
+    /// `null` for old clients not reporting their version.
+    version: Option<u32>,
+    count: u32,
+}
+
+#[derive(Serialize)]
+struct OnlineVersions {
+    total: u32,
+    /// Newest version first.
+    versions: Vec<VersionCount>,
+}
+
+#[get("/online-versions")]
+pub async fn get_online_versions(online_users: OnlineUsersData) -> impl Responder {
+    let versions = online_users
+        .lock()
+        .expect("Online users poisoned!")
+        .count_by_version();
+    web::Json(OnlineVersions {
+        total: versions.values().sum(),
+        versions: versions
+            .into_iter()
+            .rev()
+            .map(|(version, count)| VersionCount { version, count })
+            .collect(),
+    })
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VersionHistoryQuery {
+    /// UTC day, defaults to a week before `to`.
+    pub from: Option<NaiveDate>,
+    /// Inclusive UTC day, defaults to today.
+    pub to: Option<NaiveDate>,
+    #[serde(default = "default_bucket")]
+    pub bucket: Bucket,
+}
+
+fn default_bucket() -> Bucket {
+    Bucket::Hour
+}
+
+impl VersionHistoryQuery {
+    pub fn range(&self) -> anyhow::Result<DateRange> {
+        DateRange::resolve(self.from, self.to, 7, self.bucket.max_range_days())
+    }
+}
+
+#[derive(Serialize)]
+pub struct VersionHistory {
+    from: NaiveDate,
+    /// Inclusive.
+    to: NaiveDate,
+    bucket: Bucket,
+    series: Vec<VersionPoint>,
+}
+
+#[derive(Serialize)]
+struct VersionPoint {
+    /// Start of the bucket.
+    time: DateTime<Utc>,
+    version: Option<u32>,
+    /// Average over the samples in the bucket with anyone online.
+    avg: f64,
+    max: u32,
+}
+
+/// Rolls the complete hours from `?2` on up into `online_versions_hourly`. Samples without a
+/// version count as 0 in its average.
+const HOURLY_ROLLUP_SQL: &str = "\
+    with hours as ( \
+        select strftime('%Y-%m-%d %H:00:00', time) as period, count(distinct time) as samples \
+        from online_versions where time < ?1 and time >= ?2 group by period \
+    ) \
+    insert into online_versions_hourly (time, version, avg, max, samples) \
+    select period, version, cast(sum(count) as real) / hours.samples, max(count), hours.samples \
+    from online_versions join hours on strftime('%Y-%m-%d %H:00:00', time) = period \
+    where time < ?1 and time >= ?2 group by period, version;";
+
+/// Rolls the complete days from `?2` on up into `online_versions_daily` from the hourly rollups.
+const DAILY_ROLLUP_SQL: &str = "\
+    with hours as ( \
+        select time, version, avg, max, samples from online_versions_hourly \
+        where time < ?1 and time >= ?2 \
+    ), days as ( \
+        select date(time) as day, sum(samples) as samples \
+        from (select distinct time, samples from hours) group by day \
+    ) \
+    insert into online_versions_daily (time, version, avg, max, samples) \
+    select day || ' 00:00:00', version, sum(avg * hours.samples) / days.samples, max(max), \
+        days.samples \
+    from hours join days on date(time) = day group by day, version;";
+
+/// Updates the hourly and daily rollups, redoing the last period rolled up, then deletes minute
+/// samples that are older than `minute_retention` and already rolled up.
+pub async fn roll_up_at(
+    conn: &mut SqliteConnection,
+    now: NaiveDateTime,
+    minute_retention: TimeDelta,
+) -> anyhow::Result<()> {
+    for (table, rollup_sql, until) in [
+        (
+            "online_versions_hourly",
+            HOURLY_ROLLUP_SQL,
+            "%Y-%m-%d %H:00:00",
+        ),
+        (
+            "online_versions_daily",
+            DAILY_ROLLUP_SQL,
+            "%Y-%m-%d 00:00:00",
+        ),
+    ] {
+        // null versions rule out an upsert, so the last period is deleted and rolled up again
+        let last: String =
+            sqlx::query_scalar(&format!("select coalesce(max(time), '') from {table};"))
+                .fetch_one(&mut *conn)
+                .await
+                .with_context(|| format!("Could not select the last {table} rollup"))?;
+        sqlx::query(&format!("delete from {table} where time >= ?;"))
+            .bind(&last)
+            .execute(&mut *conn)
+            .await
+            .with_context(|| format!("Could not delete the last {table} rollup"))?;
+        sqlx::query(rollup_sql)
+            .bind(now.format(until).to_string())
+            .bind(&last)
+            .execute(&mut *conn)
+            .await
+            .with_context(|| format!("Could not roll up {table}"))?;
+    }
+    // keeps the samples of the last hour rolled up, it gets redone next time
+    sqlx::query(
+        "delete from online_versions where time < ? \
+         and time < (select max(time) from online_versions_hourly);",
+    )
+    .bind((now - minute_retention).to_string())
+    .execute(&mut *conn)
+    .await
+    .context("Could not delete old version samples")?;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Tier {
+    Daily,
+    Hourly,
+    Minute,
+}
+
+impl Tier {
+    /// Tables read for the bucket, coarsest first. Each one covers the time the coarser ones
+    /// haven't rolled up yet, same as the online users tiers.
+    fn of(bucket: Bucket) -> &'static [Tier] {
+        match bucket {
+            Bucket::Minute => &[Tier::Minute],
+            Bucket::Hour => &[Tier::Hourly, Tier::Minute],
+            Bucket::Day => &[Tier::Daily, Tier::Hourly, Tier::Minute],
+        }
+    }
+
+    /// Samples as `time, version, total, samples, max` rows, `total / samples` being the average.
+    fn samples_sql(self) -> &'static str {
+        match self {
+            Tier::Daily => {
+                "select time, version, avg * samples as total, samples, max \
+                 from online_versions_daily where true"
+            }
+            Tier::Hourly => {
+                "select time, version, avg * samples as total, samples, max \
+                 from online_versions_hourly where true"
+            }
+            Tier::Minute => {
+                "select time, version, cast(count as real) as total, 1 as samples, \
+                     count as max \
+                 from online_versions where true"
+            }
+        }
+    }
+
+    /// Time up to which the tier is rolled up, the minute samples are always complete.
+    fn complete_until_sql(self) -> Option<&'static str> {
+        match self {
+            Tier::Daily => Some(
+                "(select coalesce(datetime(max(time), '+1 day'), '') from online_versions_daily)",
+            ),
+            Tier::Hourly => Some(
+                "(select coalesce(datetime(max(time), '+1 hour'), '') from online_versions_hourly)",
+            ),
+            Tier::Minute => None,
+        }
+    }
+}
+
+/// Union of the tiers read for the bucket.
+fn samples_sql(bucket: Bucket) -> String {
+    let mut parts = Vec::new();
+    let mut covered_until = None;
+    for tier in Tier::of(bucket) {
+        let mut part = tier.samples_sql().to_owned();
+        if let Some(covered_until) = covered_until {
+            part += &format!(" and time >= {covered_until}");
+        }
+        if let Some(complete_until) = tier.complete_until_sql() {
+            part += &format!(" and time < {complete_until}");
+            covered_until = Some(complete_until);
+        }
+        parts.push(part);
+    }
+    parts.join(" union all ")
+}
+
+/// Online users per version, from the minute samples within the minute retention
+/// (`online_users.minute_retention_days`) and the hourly and daily rollups before.
+pub async fn get_version_history(
+    pg: &Pool<Sqlite>,
+    query: VersionHistoryQuery,
+) -> anyhow::Result<VersionHistory> {
+    let range = query.range()?;
+
+    let rows = sqlx::query(&format!(
+        "with tiers as ({samples}), periods as ( \
+             select {period} as period, sum(samples) as samples \
+             from (select distinct time, samples from tiers where time >= ?1 and time < ?2) \
+             group by period \
+         ) \
+         select periods.period as period, version, sum(total) / periods.samples as avg, \
+             max(max) as max \
+         from tiers join periods on {period} = periods.period \
+         where time >= ?1 and time < ?2 \
+         group by periods.period, version order by periods.period, version;",
+        samples = samples_sql(query.bucket),
+        period = query.bucket.period_sql()
+    ))
+    .bind(range.start_time())
+    .bind(range.end_time())
+    .fetch_all(pg)
+    .await
+    .context("Could not select version samples")?;
+    let series = rows
+        .into_iter()
+        .map(|row| {
+            Ok(VersionPoint {
+                time: NaiveDateTime::parse_from_str(row.get("period"), "%Y-%m-%d %H:%M:%S")
+                    .context("Invalid period")?
+                    .and_utc(),
+                version: row
+                    .get::<Option<i64>, _>("version")
+                    .map(|version| version as u32),
+                avg: (row.get::<f64, _>("avg") * 10.0).round() / 10.0,
+                max: row.get::<i64, _>("max") as u32,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(VersionHistory {
+        from: range.from,
+        to: range.to,
+        bucket: query.bucket,
+        series,
+    })
+}
+
+#[derive(Clone)]
+pub struct VersionHistoryJson(String);
+
+impl VersionHistoryJson {
+    pub fn memoized_by_query(pg: Pool<Sqlite>) -> MemoizedMap<VersionHistoryQuery, Self> {
+        MemoizedMap::builder(Duration::from_secs(60), MAX_CACHED_QUERIES).build(move |query| {
+            let pg = Pool::clone(&pg);
+            async move {
+                debug!("Generating new version history json for {query:?}...");
+                let history = get_version_history(&pg, query).await?;
+                let json = serde_json::to_string(&history)
+                    .context("Could not serialize version history")?;
+                Ok(Self(json))
+            }
+        })
+    }
+}
+
+#[get("/online-versions/history")]
+pub async fn get_history(
+    query: web::Query<VersionHistoryQuery>,
+    history: web::Data<MemoizedMap<VersionHistoryQuery, VersionHistoryJson>>,
+) -> actix_web::Result<impl Responder> {
+    query
+        .range()
+        .map_err(|err| actix_web::error::ErrorBadRequest(format!("{err:#}")))?;
+    let history = history.get(*query).await.map_err(|err| {
+        error!("Could not get version history for {query:?}: {err:#}");
+        actix_web::error::ErrorInternalServerError("could not get version history")
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(history.age_header())
+        .body(history.value.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[actix_web::test]
+    pub async fn test_version_history() {
+        let pg = db::test_pool().await;
+        for (time, version, count) in [
+            ("2026-10-05 10:00:00", Some(16), 4),
+            ("2026-10-05 10:00:00", Some(17), 2),
+            ("2026-10-05 10:01:00", Some(17), 6),
+            ("2026-10-05 10:01:00", None, 1),
+            ("2026-10-05 11:00:00", Some(17), 3),
+        ] {
+            sqlx::query("insert into online_versions (time, version, count) values (?, ?, ?);")
+                .bind(time)
+                .bind(version)
+                .bind(count)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+        let query = VersionHistoryQuery {
+            from: Some(NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()),
+            to: Some(NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()),
+            bucket: Bucket::Hour,
+        };
+        let history = get_version_history(&pg, query).await.unwrap();
+        let series: Vec<_> = history
+            .series
+            .iter()
+            .map(|point| {
+                (
+                    point.time.format("%H").to_string(),
+                    point.version,
+                    point.avg,
+                    point.max,
+                )
+            })
+            .collect();
+        assert_eq!(
+            series,
+            [
+                (String::from("10"), None, 0.5, 1),
+                (String::from("10"), Some(16), 2.0, 4),
+                (String::from("10"), Some(17), 4.0, 6),
+                (String::from("11"), Some(17), 3.0, 3),
+            ]
+        );
+
+        let query = VersionHistoryQuery {
+            from: None,
+            to: Some(NaiveDate::MIN),
+            ..query
+        };
+        assert!(get_version_history(&pg, query).await.is_err());
+    }
+
+    #[actix_web::test]
+    pub async fn test_roll_up() {
+        let pg = db::test_pool().await;
+        for (time, version, count) in [
+            ("2026-10-05 10:00:00", Some(16), 4),
+            ("2026-10-05 10:00:00", Some(17), 2),
+            ("2026-10-05 10:01:00", Some(17), 6),
+            ("2026-10-05 10:01:00", None, 1),
+            ("2026-10-05 11:00:00", Some(17), 3),
+            ("2026-10-06 10:00:00", Some(17), 5),
+            ("2026-10-08 12:29:00", Some(17), 7),
+        ] {
+            sqlx::query("insert into online_versions (time, version, count) values (?, ?, ?);")
+                .bind(time)
+                .bind(version)
+                .bind(count)
+                .execute(&pg)
+                .await
+                .unwrap();
+        }
+        let series = |history: VersionHistory| -> Vec<_> {
+            history
+                .series
+                .into_iter()
+                .map(|point| (point.time, point.version, point.avg, point.max))
+                .collect()
+        };
+        let queries = [Bucket::Hour, Bucket::Day].map(|bucket| VersionHistoryQuery {
+            from: Some(NaiveDate::from_ymd_opt(2026, 10, 5).unwrap()),
+            to: Some(NaiveDate::from_ymd_opt(2026, 10, 8).unwrap()),
+            bucket,
+        });
+        let mut before = Vec::new();
+        for query in queries {
+            before.push(series(get_version_history(&pg, query).await.unwrap()));
+        }
+
+        let now = "2026-10-08T12:30:00".parse::<NaiveDateTime>().unwrap();
+        for _ in 0..2 {
+            let mut conn = pg.acquire().await.unwrap();
+            roll_up_at(&mut conn, now, TimeDelta::days(1))
+                .await
+                .unwrap();
+        }
+        let minutes: Vec<String> =
+            sqlx::query_scalar("select time from online_versions order by time;")
+                .fetch_all(&pg)
+                .await
+                .unwrap();
+        // the last hour rolled up is kept to be redone, the current one isn't rolled up yet
+        assert_eq!(minutes, ["2026-10-06 10:00:00", "2026-10-08 12:29:00"]);
+        let days: Vec<(String, Option<i64>, f64)> = sqlx::query_as(
+            "select time, version, avg from online_versions_daily order by time, version;",
+        )
+        .fetch_all(&pg)
+        .await
+        .unwrap();
+        assert_eq!(
+            days,
+            [
+                (String::from("2026-10-05 00:00:00"), None, 1.0 / 3.0),
+                (String::from("2026-10-05 00:00:00"), Some(16), 4.0 / 3.0),
+                (String::from("2026-10-05 00:00:00"), Some(17), 11.0 / 3.0),
+                (String::from("2026-10-06 00:00:00"), Some(17), 5.0),
+            ]
+        );
+
+        for (query, before) in queries.iter().zip(before) {
+            assert_eq!(
+                series(get_version_history(&pg, *query).await.unwrap()),
+                before
+            );
+        }
+    }
+}
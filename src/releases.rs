@@ -0,0 +1,555 @@
+use crate::bc::Version;
+use crate::file_host::{FileHost, FileIntegrity};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+
+#[derive(Serialize, sqlx::FromRow, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Release {
+    pub version: u32,
+    pub released_at: DateTime<Utc>,
+    pub changelog: String,
+    /// Name of the file in `FileHost`.
+    pub file: String,
+    /// Clients older than this version must update.
+    pub min_supported_version: u32,
+    pub mandatory: bool,
+    /// `None` if the release is offered to every platform.
+    pub platform: Option<String>,
+    pub channel: Channel,
+    /// Percentage of clients the release is offered to.
+    pub rollout_percentage: u8,
+    pub rollout_paused: bool,
+}
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    Stable,
+    Beta,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewRelease {
+    pub version: u32,
+    #[serde(default)]
+    pub changelog: String,
+    pub file: String,
+    #[serde(default)]
+    pub min_supported_version: u32,
+    #[serde(default)]
+    pub mandatory: bool,
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub channel: Channel,
+    #[serde(default = "full_rollout")]
+    pub rollout_percentage: u8,
+}
+
+fn full_rollout() -> u8 {
+    100
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollout {
+    pub percentage: u8,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Which releases a client may be offered.
+pub struct ReleaseFilter<'a> {
+    pub platform: Option<&'a str>,
+    /// Beta clients are offered stable releases too.
+    pub channel: Channel,
+    /// Client identifier (or its ip) used for rollout bucketing.
+    pub client_key: &'a str,
+}
+
+impl Release {
+    fn is_offered_to(&self, filter: &ReleaseFilter) -> bool {
+        !self.rollout_paused
+            && rollout_bucket(self.version, filter.client_key) < self.rollout_percentage
+    }
+}
+
+/// Deterministic bucket in `0..100` of a client for the given release. Bucketing per release
+/// keeps a client in the rollout when its percentage is widened.
+fn rollout_bucket(version: u32, client_key: &str) -> u8 {
+    let digest = Sha256::new()
+        .chain_update(version.to_be_bytes())
+        .chain_update(client_key.as_bytes())
+        .finalize();
+    let head = u32::from_be_bytes(
+        digest[..4]
+            .try_into()
+            .expect("digest is longer than 4 bytes"),
+    );
+    (head % 100) as u8
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateAction {
+    UpToDate,
+    OptionalUpdate,
+    ForcedUpdate,
+}
+
+impl UpdateAction {
+    /// Decides what a client running `current` version should do given the releases newer
+    /// than its version (oldest first).
+    pub fn decide(current: u32, pending: &[Release]) -> Self {
+        let latest = match pending.last() {
+            None => return Self::UpToDate,
+            Some(latest) => latest,
+        };
+        if current < latest.min_supported_version || pending.iter().any(|r| r.mandatory) {
+            Self::ForcedUpdate
+        } else {
+            Self::OptionalUpdate
+        }
+    }
+}
+
+pub struct ReleaseCatalog {
+    pg: Pool<Sqlite>,
+}
+
+impl ReleaseCatalog {
+    pub fn new(pg: Pool<Sqlite>) -> Self {
+        Self { pg }
+    }
+
+    pub async fn publish(&self, release: &NewRelease) -> anyhow::Result<()> {
+        let mut tx = self
+            .pg
+            .begin()
+            .await
+            .context("Could not begin transaction")?;
+        sqlx::query(
+            "insert into releases \
+                 (version, released_at, changelog, file, min_supported_version, mandatory, \
+                  platform, channel) \
+             values (?, datetime('now'), ?, ?, ?, ?, ?, ?);",
+        )
+        .bind(release.version)
+        .bind(&release.changelog)
+        .bind(&release.file)
+        .bind(release.min_supported_version)
+        .bind(release.mandatory)
+        .bind(&release.platform)
+        .bind(release.channel)
+        .execute(&mut *tx)
+        .await
+        .context("Could not insert release")?;
+        sqlx::query(
+            "insert into rollouts (version, percentage, updated_at) \
+             values (?, ?, datetime('now'));",
+        )
+        .bind(release.version)
+        .bind(release.rollout_percentage)
+        .execute(&mut *tx)
+        .await
+        .context("Could not insert rollout")?;
+        tx.commit().await.context("Could not commit release")
+    }
+
+    /// Returns false if there is no such release.
+    pub async fn set_rollout(&self, version: u32, rollout: &Rollout) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "insert into rollouts (version, percentage, paused, updated_at) \
+             select version, ?2, ?3, datetime('now') from releases where version = ?1 \
+             on conflict (version) do update \
+             set percentage = excluded.percentage, paused = excluded.paused, \
+                 updated_at = excluded.updated_at;",
+        )
+        .bind(version)
+        .bind(rollout.percentage)
+        .bind(rollout.paused)
+        .execute(&self.pg)
+        .await
+        .context("Could not update rollout")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn latest(&self, filter: &ReleaseFilter<'_>) -> anyhow::Result<Option<Release>> {
+        Ok(self.since(0, filter).await?.pop())
+    }
+
+    /// Releases offered to the client which are newer than `version`, oldest first.
+    pub async fn since(
+        &self,
+        version: u32,
+        filter: &ReleaseFilter<'_>,
+    ) -> anyhow::Result<Vec<Release>> {
+        let releases: Vec<Release> = sqlx::query_as(
+            "select releases.*, \
+                 coalesce(rollouts.percentage, 100) as rollout_percentage, \
+                 coalesce(rollouts.paused, false) as rollout_paused \
+             from releases left join rollouts on rollouts.version = releases.version \
+             where releases.version > ?1 \
+                 and (?2 is null or platform is null or platform = ?2) \
+                 and (channel = 'stable' or channel = ?3) \
+             order by releases.version;",
+        )
+        .bind(version)
+        .bind(filter.platform)
+        .bind(filter.channel)
+        .fetch_all(&self.pg)
+        .await
+        .context("Could not select releases")?;
+        Ok(releases
+            .into_iter()
+            .filter(|release| release.is_offered_to(filter))
+            .collect())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientQuery {
+    #[serde(default)]
+    channel: Channel,
+    client_id: Option<String>,
+    platform: Option<String>,
+}
+
+impl ClientQuery {
+    fn filter<'a>(&'a self, ip: &'a str) -> ReleaseFilter<'a> {
+        ReleaseFilter {
+            platform: self.platform.as_deref(),
+            channel: self.channel,
+            client_key: self.client_id.as_deref().unwrap_or(ip),
+        }
+    }
+}
+
+pub fn real_ip(req: &HttpRequest) -> String {
+    req.connection_info()
+        .realip_remote_addr()
+        .expect("Request ip must be present!")
+        .to_owned()
+}
+
+/// Plain text version for old clients, which are bucketed by ip on the stable channel. The
+/// configured version is only a fallback for clients which don't send a platform, it may not
+/// exist for the ones that do.
+#[get("/version")]
+pub async fn get_version(
+    req: HttpRequest,
+    version: web::Data<Version>,
+    catalog: web::Data<ReleaseCatalog>,
+    query: web::Query<ClientQuery>,
+) -> actix_web::Result<String> {
+    let ip = real_ip(&req);
+    let latest = match (catalog.latest(&query.filter(&ip)).await, &query.platform) {
+        (Ok(latest), _) => latest.map(|release| release.version),
+        (Err(err), Some(_)) => return Err(internal_error(err)),
+        (Err(err), None) => {
+            error!("Could not get latest release, falling back to configured version: {err:#}");
+            None
+        }
+    };
+    match (latest, &query.platform) {
+        (Some(latest), _) => Ok(latest.to_string()),
+        (None, None) => Ok(version.0.to_string()),
+        (None, Some(platform)) => Err(actix_web::error::ErrorNotFound(format!(
+            "no release for platform {platform}"
+        ))),
+    }
+}
+
+#[get("/releases/latest")]
+pub async fn get_latest(
+    req: HttpRequest,
+    catalog: web::Data<ReleaseCatalog>,
+    query: web::Query<ClientQuery>,
+) -> actix_web::Result<impl Responder> {
+    let ip = real_ip(&req);
+    let latest = catalog
+        .latest(&query.filter(&ip))
+        .await
+        .map_err(internal_error)?;
+    Ok(match latest {
+        None => HttpResponse::NotFound().finish(),
+        Some(release) => HttpResponse::Ok().json(release),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SinceQuery {
+    since: u32,
+}
+
+#[get("/releases")]
+pub async fn get_since(
+    req: HttpRequest,
+    catalog: web::Data<ReleaseCatalog>,
+    since: web::Query<SinceQuery>,
+    query: web::Query<ClientQuery>,
+) -> actix_web::Result<impl Responder> {
+    let ip = real_ip(&req);
+    let releases = catalog
+        .since(since.since, &query.filter(&ip))
+        .await
+        .map_err(internal_error)?;
+    Ok(HttpResponse::Ok().json(releases))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateCheckQuery {
+    version: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateCheckResponse {
+    action: UpdateAction,
+    latest_version: u32,
+    release: Option<Release>,
+    download: Option<UpdateDownload>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateDownload {
+    url: String,
+    #[serde(flatten)]
+    integrity: FileIntegrity,
+}
+
+#[get("/update-check")]
+pub async fn update_check(
+    req: HttpRequest,
+    catalog: web::Data<ReleaseCatalog>,
+    file_host: web::Data<FileHost>,
+    current: web::Query<UpdateCheckQuery>,
+    query: web::Query<ClientQuery>,
+) -> actix_web::Result<impl Responder> {
+    let ip = real_ip(&req);
+    let pending = catalog
+        .since(current.version, &query.filter(&ip))
+        .await
+        .map_err(internal_error)?;
+    let action = UpdateAction::decide(current.version, &pending);
+    let release = pending.into_iter().last();
+    let download = match &release {
+        None => None,
+        Some(release) => {
+            let (file_name, integrity) = file_host.integrity(&release.file).ok_or_else(|| {
+                error!(
+                    "Release {} points to unknown or unreadable file '{}'",
+                    release.version, release.file
+                );
+                actix_web::error::ErrorInternalServerError("release file is missing")
+            })?;
+            let connection_info = req.connection_info();
+            Some(UpdateDownload {
+                url: format!(
+                    "{}://{}/download/{file_name}",
+                    connection_info.scheme(),
+                    connection_info.host()
+                ),
+                integrity,
+            })
+        }
+    };
+    Ok(HttpResponse::Ok().json(UpdateCheckResponse {
+        action,
+        latest_version: release.as_ref().map_or(current.version, |r| r.version),
+        release,
+        download,
+    }))
+}
+
+fn internal_error(err: anyhow::Error) -> actix_web::Error {
+    error!("Could not get releases: {err:#}");
+    actix_web::error::ErrorInternalServerError("could not get releases")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    fn filter(platform: Option<&str>) -> ReleaseFilter<'_> {
+        ReleaseFilter {
+            platform,
+            channel: Channel::Stable,
+            client_key: "127.0.0.1",
+        }
+    }
+
+    async fn create_catalog() -> (Pool<Sqlite>, ReleaseCatalog) {
+        let pg = db::test_pool().await;
+        (pg.clone(), ReleaseCatalog::new(pg))
+    }
+
+    #[actix_web::test]
+    pub async fn test_catalog() {
+        let (pg, catalog) = create_catalog().await;
+        assert!(catalog.latest(&filter(None)).await.unwrap().is_none());
+
+        for version in [15, 16, 17] {
+            sqlx::query(
+                "insert into releases (version, released_at, file, mandatory) \
+                 values (?, datetime('now'), 'BuzkaaClicker', ?);",
+            )
+            .bind(version)
+            .bind(version == 16)
+            .execute(&pg)
+            .await
+            .unwrap();
+        }
+        let latest = catalog.latest(&filter(None)).await.unwrap().unwrap();
+        assert_eq!(latest.version, 17);
+        let since = catalog.since(15, &filter(None)).await.unwrap();
+        assert_eq!(
+            since
+                .iter()
+                .map(|release| release.version)
+                .collect::<Vec<_>>(),
+            [16, 17]
+        );
+        assert!(since[0].mandatory);
+        assert_eq!(UpdateAction::decide(15, &since), UpdateAction::ForcedUpdate);
+        assert_eq!(
+            UpdateAction::decide(16, &since[1..]),
+            UpdateAction::OptionalUpdate
+        );
+        assert_eq!(UpdateAction::decide(17, &[]), UpdateAction::UpToDate);
+
+        sqlx::query("update releases set platform = 'linux', min_supported_version = 17 where version = 17;")
+            .execute(&pg)
+            .await
+            .unwrap();
+        assert!(catalog
+            .since(16, &filter(Some("windows")))
+            .await
+            .unwrap()
+            .is_empty());
+        let since = catalog.since(16, &filter(Some("linux"))).await.unwrap();
+        assert_eq!(UpdateAction::decide(16, &since), UpdateAction::ForcedUpdate);
+    }
+
+    #[actix_web::test]
+    pub async fn test_rollout() {
+        let (pg, catalog) = create_catalog().await;
+        sqlx::query(
+            "insert into releases (version, released_at, file, channel) \
+             values (16, datetime('now'), 'BuzkaaClicker', 'stable'), \
+                    (17, datetime('now'), 'BuzkaaClicker', 'beta');",
+        )
+        .execute(&pg)
+        .await
+        .unwrap();
+        let mut beta = filter(None);
+        beta.channel = Channel::Beta;
+        assert_eq!(
+            catalog
+                .latest(&filter(None))
+                .await
+                .unwrap()
+                .unwrap()
+                .version,
+            16
+        );
+        assert_eq!(catalog.latest(&beta).await.unwrap().unwrap().version, 17);
+
+        sqlx::query("insert into rollouts (version, percentage, updated_at) values (17, 10, datetime('now'));")
+            .execute(&pg)
+            .await
+            .unwrap();
+        let clients: Vec<_> = (0..1000).map(|i| format!("client-{i}")).collect();
+        let mut offered = 0;
+        for client in &clients {
+            let filter = ReleaseFilter {
+                client_key: client,
+                ..beta
+            };
+            if catalog.latest(&filter).await.unwrap().unwrap().version == 17 {
+                offered += 1;
+            }
+        }
+        assert!((50..150).contains(&offered), "offered to {offered} clients");
+
+        sqlx::query("update rollouts set paused = true where version = 17;")
+            .execute(&pg)
+            .await
+            .unwrap();
+        for client in &clients[..100] {
+            let filter = ReleaseFilter {
+                client_key: client,
+                ..beta
+            };
+            assert_eq!(catalog.latest(&filter).await.unwrap().unwrap().version, 16);
+        }
+    }
+
+    #[actix_web::test]
+    pub async fn test_get_version() {
+        let (pg, catalog) = create_catalog().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Version(16)))
+                .app_data(web::Data::new(catalog))
+                .service(get_version),
+        )
+        .await;
+        let get = |uri: &'static str| {
+            let app = &app;
+            async move {
+                let res = test::call_service(
+                    app,
+                    TestRequest::get()
+                        .uri(uri)
+                        .peer_addr("127.0.0.1:2137".parse().unwrap())
+                        .to_request(),
+                )
+                .await;
+                let status = res.status();
+                (status, test::read_body(res).await)
+            }
+        };
+        assert_eq!(get("/version").await, (StatusCode::OK, "16".into()));
+        assert_eq!(
+            get("/version?platform=linux").await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        sqlx::query(
+            "insert into releases (version, released_at, file, platform) \
+             values (17, datetime('now'), 'BuzkaaClicker', 'linux');",
+        )
+        .execute(&pg)
+        .await
+        .unwrap();
+        assert_eq!(
+            get("/version?platform=linux").await,
+            (StatusCode::OK, "17".into())
+        );
+        assert_eq!(
+            get("/version?platform=windows").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    pub fn test_rollout_bucket_is_stable() {
+        assert_eq!(rollout_bucket(17, "abc"), rollout_bucket(17, "abc"));
+        assert!((0..1000).all(|i| rollout_bucket(i, "abc") < 100));
+    }
+}
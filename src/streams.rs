@@ -0,0 +1,267 @@
+use crate::cache::MemoizedMap;
+use crate::date_range::{DateRange, MAX_CACHED_QUERIES};
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::time::Duration;
+
+const DEFAULT_RANGE_DAYS: u64 = 90;
+const MAX_RANGE_DAYS: u64 = 3 * 366;
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// A live stream as seen by one live status check.
+pub struct SeenStream<'a> {
+    pub video_id: &'a str,
+    pub channel_id: &'a str,
+    pub title: &'a str,
+    pub started_at: DateTime<Utc>,
+    pub viewers: Option<u32>,
+}
+
+/// Records the stream, or updates its last seen time, title and peak viewers when it's already
+/// known. Returns whether the stream is new.
+pub async fn record_stream(pg: &Pool<Sqlite>, stream: &SeenStream<'_>) -> anyhow::Result<bool> {
+    let now = Utc::now().format(TIME_FORMAT).to_string();
+    let inserted = sqlx::query(
+        "insert into streams \
+             (video_id, channel_id, title, started_at, first_seen, last_seen, peak_viewers) \
+         values (?, ?, ?, ?, ?, ?, ?) \
+         on conflict (video_id) do nothing;",
+    )
+    .bind(stream.video_id)
+    .bind(stream.channel_id)
+    .bind(stream.title)
+    .bind(stream.started_at.format(TIME_FORMAT).to_string())
+    .bind(&now)
+    .bind(&now)
+    .bind(stream.viewers)
+    .execute(pg)
+    .await
+    .context("Could not record stream")?
+    .rows_affected();
+    if inserted > 0 {
+        info!(
+            "New stream of {}: '{}' ({}).",
+            stream.channel_id, stream.title, stream.video_id
+        );
+        return Ok(true);
+    }
+    sqlx::query(
+        "update streams set title = ?, last_seen = ?, \
+             peak_viewers = coalesce(max(peak_viewers, ?), peak_viewers, ?) \
+         where video_id = ?;",
+    )
+    .bind(stream.title)
+    .bind(&now)
+    .bind(stream.viewers)
+    .bind(stream.viewers)
+    .bind(stream.video_id)
+    .execute(pg)
+    .await
+    .context("Could not update stream")?;
+    Ok(false)
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamsQuery {
+    /// UTC day, defaults to 90 days before `to`.
+    pub from: Option<NaiveDate>,
+    /// Inclusive UTC day, defaults to today.
+    pub to: Option<NaiveDate>,
+    /// Youtube channel id, all channels when missing.
+    pub channel: Option<String>,
+}
+
+impl StreamsQuery {
+    pub fn range(&self) -> anyhow::Result<DateRange> {
+        DateRange::resolve(self.from, self.to, DEFAULT_RANGE_DAYS, MAX_RANGE_DAYS)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Streams {
+    from: NaiveDate,
+    /// Inclusive.
+    to: NaiveDate,
+    /// Newest first.
+    streams: Vec<Stream>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Stream {
+    video_id: String,
+    url: String,
+    channel_id: String,
+    title: String,
+    started_at: DateTime<Utc>,
+    first_seen: DateTime<Utc>,
+    /// Close to the end of the stream, live status is checked about once a minute.
+    last_seen: DateTime<Utc>,
+    peak_viewers: Option<u32>,
+}
+
+/// Streams that were live at some point of the range.
+pub async fn get_streams(pg: &Pool<Sqlite>, query: &StreamsQuery) -> anyhow::Result<Streams> {
+    let range = query.range()?;
+    let rows = sqlx::query(
+        "select video_id, channel_id, title, started_at, first_seen, last_seen, peak_viewers \
+         from streams where last_seen >= ? and first_seen < ? \
+             and (? is null or channel_id = ?) \
+         order by first_seen desc;",
+    )
+    .bind(range.start_time())
+    .bind(range.end_time())
+    .bind(&query.channel)
+    .bind(&query.channel)
+    .fetch_all(pg)
+    .await
+    .context("Could not select streams")?;
+    let time = |row: &sqlx::sqlite::SqliteRow, column: &str| -> anyhow::Result<DateTime<Utc>> {
+        Ok(NaiveDateTime::parse_from_str(row.get(column), TIME_FORMAT)
+            .with_context(|| format!("Invalid {column}"))?
+            .and_utc())
+    };
+    let streams = rows
+        .iter()
+        .map(|row| {
+            let video_id: String = row.get("video_id");
+            Ok(Stream {
+                url: format!("https://www.youtube.com/watch?v={video_id}"),
+                video_id,
+                channel_id: row.get("channel_id"),
+                title: row.get("title"),
+                started_at: time(row, "started_at")?,
+                first_seen: time(row, "first_seen")?,
+                last_seen: time(row, "last_seen")?,
+                peak_viewers: row
+                    .get::<Option<i64>, _>("peak_viewers")
+                    .map(|viewers| viewers as u32),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Streams {
+        from: range.from,
+        to: range.to,
+        streams,
+    })
+}
+
+#[derive(Clone)]
+pub struct StreamsJson(String);
+
+impl StreamsJson {
+    pub fn memoized_by_query(pg: Pool<Sqlite>) -> MemoizedMap<StreamsQuery, Self> {
+        MemoizedMap::builder(Duration::from_secs(60), MAX_CACHED_QUERIES).build(
+            move |query: StreamsQuery| {
+                let pg = Pool::clone(&pg);
+                async move {
+                    debug!("Generating new streams json for {query:?}...");
+                    let streams = get_streams(&pg, &query).await?;
+                    let json =
+                        serde_json::to_string(&streams).context("Could not serialize streams")?;
+                    Ok(Self(json))
+                }
+            },
+        )
+    }
+}
+
+#[get("/streams")]
+pub async fn list_streams(
+    query: web::Query<StreamsQuery>,
+    streams: web::Data<MemoizedMap<StreamsQuery, StreamsJson>>,
+) -> actix_web::Result<impl Responder> {
+    query
+        .range()
+        .map_err(|err| actix_web::error::ErrorBadRequest(format!("{err:#}")))?;
+    let streams = streams.get(query.0.clone()).await.map_err(|err| {
+        error!("Could not get streams for {query:?}: {err:#}");
+        actix_web::error::ErrorInternalServerError("could not get streams")
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(streams.age_header())
+        .body(streams.value.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use chrono::Days;
+
+    #[actix_web::test]
+    pub async fn test_record_streams() {
+        let pg = db::test_pool().await;
+        let started_at = Utc::now();
+        let mut stream = SeenStream {
+            video_id: "bKqZ4nQ2x7w",
+            channel_id: "UCL1s7OtDPaX3SdhW5433PRw",
+            title: "Kopiemy kamienie",
+            started_at,
+            viewers: None,
+        };
+        assert!(record_stream(&pg, &stream).await.unwrap());
+        // seen again within the same second, still not new
+        for viewers in [Some(214), Some(120), None] {
+            stream.viewers = viewers;
+            stream.title = "Kopiemy kamienie z widzami!";
+            assert!(!record_stream(&pg, &stream).await.unwrap());
+        }
+        assert!(record_stream(
+            &pg,
+            &SeenStream {
+                video_id: "Tn8vR2kEo1M",
+                channel_id: "partner",
+                title: "Partner stream",
+                started_at,
+                viewers: Some(5),
+            },
+        )
+        .await
+        .unwrap());
+
+        let streams = get_streams(
+            &pg,
+            &StreamsQuery {
+                from: None,
+                to: None,
+                channel: Some(String::from("UCL1s7OtDPaX3SdhW5433PRw")),
+            },
+        )
+        .await
+        .unwrap()
+        .streams;
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].title, "Kopiemy kamienie z widzami!");
+        assert_eq!(streams[0].peak_viewers, Some(214));
+        assert_eq!(
+            streams[0].url,
+            "https://www.youtube.com/watch?v=bKqZ4nQ2x7w"
+        );
+        assert_eq!(streams[0].started_at.timestamp(), started_at.timestamp());
+
+        let all = StreamsQuery {
+            from: None,
+            to: None,
+            channel: None,
+        };
+        assert_eq!(get_streams(&pg, &all).await.unwrap().streams.len(), 2);
+        let before = StreamsQuery {
+            to: Some(Utc::now().date_naive() - Days::new(1)),
+            ..all
+        };
+        assert!(get_streams(&pg, &before).await.unwrap().streams.is_empty());
+        let overflowing = StreamsQuery {
+            to: Some(NaiveDate::MAX),
+            ..before
+        };
+        assert!(get_streams(&pg, &overflowing).await.is_err());
+    }
+}
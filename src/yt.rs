@@ -1,121 +1,104 @@
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crate::cache::Memoized;
-use actix_web::http::header::ContentType;
+use crate::config::{ChannelConfig, LiveProviderKind, YoutubeConfig};
+use actix_web::http::header::{ContentType, AGE};
 use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
 use anyhow::{anyhow, Context};
 use chrono::{DateTime, FixedOffset};
-use log::{debug, error, info};
-use scraper::{Html, Selector};
+use futures::future;
+use log::{debug, error, warn};
 use serde::Serialize;
+use sqlx::{Pool, Sqlite};
 
+use crate::notifications::{LiveStream, Notifier};
 use crate::online_users::OnlineUsersData;
+use crate::streams::{self, SeenStream};
 
-const CHANNEL_ID: &str = "UCL1s7OtDPaX3SdhW5433PRw";
-const LIVE_URL: &str = "https://www.youtube.com/channel/UCL1s7OtDPaX3SdhW5433PRw/live";
+pub mod data_api;
+mod scraper;
 
-pub struct LiveVisitor {
-    name_sel: Selector,
-    start_date_sel: Selector,
-    canonical_sel: Selector,
-}
+type LiveFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Option<LiveMeta>>> + 'a>>;
 
-impl LiveVisitor {
-    pub fn new() -> Self {
-        let name_sel = Selector::parse(r#"#watch7-content > meta[itemprop="name"]"#)
-            .expect("Invalid name selector");
-        let start_date_sel = Selector::parse(r#"#watch7-content > * > meta[itemprop="startDate"]"#)
-            .expect("Invalid name selector");
-        let canonical_sel =
-            Selector::parse(r#"link[rel="canonical"]"#).expect("Invalid canonical selector");
-        Self {
-            name_sel,
-            start_date_sel,
-            canonical_sel,
-        }
-    }
+/// Source of a channel's live status.
+pub trait LiveProvider: Send + Sync {
+    fn name(&self) -> &'static str;
 
-    pub async fn visit(&self) -> anyhow::Result<Option<LiveMeta>> {
-        let live_url = match self.get_live_url().await.context("Could not get live url")? {
-            None => return Ok(None),
-            Some(url) => url,
-        };
-        info!("Live url: {live_url}");
-        let live_meta = self.get_live_meta(&live_url).await?;
-        Ok(live_meta)
-    }
+    /// Resolves to `None` while the channel is neither live nor has a stream scheduled.
+    fn live_meta<'a>(&'a self, channel_id: &'a str) -> LiveFuture<'a>;
+}
 
-    async fn get_live_url(&self) -> anyhow::Result<Option<String>> {
-        let client = Self::get_awc();
-        let mut res = client
-            .get(LIVE_URL)
-            .send()
-            .await
-            .map_err(|err| anyhow!("Could not visit youtube channel page: {}", err))?;
-        let body = res
-            .body()
-            .await
-            .context("Could not read youtube channel/live body")?;
-        let body = String::from_utf8_lossy(&body);
-        let document = Html::parse_document(&body);
-        let url_element = match document.select(&self.canonical_sel).next() {
-            None => return Ok(None),
-            Some(element) => element,
-        };
-        let url = url_element
-            .value()
-            .attr("href")
-            .context("Could not select canonical href!")?;
-        Ok(Some(url.to_string()))
-    }
+/// Providers tried in order until one of them answers.
+pub struct LiveProviders(Vec<Box<dyn LiveProvider>>);
 
-    // aint gonna pay for api
-    async fn get_live_meta(&self, url: &str) -> anyhow::Result<Option<LiveMeta>> {
-        let client = Self::get_awc();
-        let mut res = client
-            .get(url)
-            .send()
-            .await
-            .map_err(|err| anyhow!("Could not visit youtube channel page: {}", err))?;
-        let body = res
-            .body()
-            .await
-            .context("Could not read youtube channel page body")?;
-        let body = String::from_utf8_lossy(&body);
-        let document = Html::parse_document(&body);
-        let title = match document.select(&self.name_sel).next() {
-            None => return Ok(None),
-            Some(element) => element
-                .value()
-                .attr("content")
-                .context("Could not select title content")?
-                .to_owned(),
-        };
-        let start_date_raw = match document.select(&self.start_date_sel).next() {
-            None => return Ok(None),
-            Some(element) => element
-                .value()
-                .attr("content")
-                .context("Could not select start date content")?,
-        };
-        let start_date =
-            DateTime::parse_from_rfc3339(start_date_raw).context("Could not parse start date!")?;
-        debug!("Live stream scrapped. Title: '{title}', start date: '{start_date_raw}'");
-        Ok(Some(LiveMeta { title, start_date }))
+impl LiveProviders {
+    pub fn from_config(config: &YoutubeConfig) -> anyhow::Result<Self> {
+        let providers = config
+            .providers
+            .iter()
+            .map(|kind| -> anyhow::Result<Box<dyn LiveProvider>> {
+                Ok(match kind {
+                    LiveProviderKind::Scraper => {
+                        Box::new(scraper::Scraper::new(config.scraper_base_url.clone()))
+                    }
+                    LiveProviderKind::DataApi => Box::new(data_api::DataApi::new(
+                        config.data_api_base_url.clone(),
+                        config
+                            .data_api_key
+                            .clone()
+                            .context("youtube.data_api_key is not set")?,
+                        Duration::from_secs(config.data_api_interval_secs),
+                    )),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(providers))
     }
 
-    fn get_awc() -> awc::Client {
-        awc::Client::builder()
-            .add_default_header(("user-agent", "curl")) // possibly provides GPDR bypass?
-            .finish()
+    pub async fn live_meta(&self, channel_id: &str) -> anyhow::Result<Option<LiveMeta>> {
+        let mut last_err = None;
+        for provider in &self.0 {
+            match provider.live_meta(channel_id).await {
+                Ok(meta) => return Ok(meta),
+                Err(err) => {
+                    warn!(
+                        "Live provider {} failed for {channel_id}: {err:#}",
+                        provider.name()
+                    );
+                    last_err = Some(err.context(format!("{} failed", provider.name())));
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No live providers configured")))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct LiveMeta {
+    video_id: String,
     title: String,
+    /// Scheduled start time of upcoming streams.
     start_date: DateTime<FixedOffset>,
+    thumbnail_url: Option<String>,
+    /// Concurrent viewers, if the provider can tell.
+    viewers: Option<u32>,
+    /// Upcoming rather than live.
+    scheduled: bool,
+}
+
+impl LiveMeta {
+    fn to_live_stream(&self) -> LiveStream {
+        LiveStream {
+            video_id: self.video_id.clone(),
+            title: self.title.clone(),
+            started_at: self.start_date.to_utc(),
+            thumbnail_url: self.thumbnail_url.clone(),
+            viewers: self.viewers,
+        }
+    }
 }
 
 #[derive(Serialize, Default, Clone)]
@@ -127,82 +110,316 @@ struct LiveResponse {
     pub live_streaming: bool,
     pub live_stream_url: String,
     pub live_stream_start_time: String,
+    pub live_stream_video_id: String,
+    pub live_stream_thumbnail_url: String,
+    pub live_stream_viewers: Option<u32>,
+    /// Scheduled streams fill the other fields too, but aren't `live_streaming` yet.
+    pub live_stream_scheduled: bool,
 }
 
-impl From<Option<LiveMeta>> for LiveResponse {
-    fn from(meta_maybe: Option<LiveMeta>) -> Self {
+impl LiveResponse {
+    fn new(channel: &ChannelConfig, meta_maybe: Option<LiveMeta>) -> Self {
         match meta_maybe {
             None => LiveResponse {
-                id: String::from(CHANNEL_ID),
+                id: channel.id.clone(),
                 name: String::new(),
                 live_stream_title: String::new(),
                 live_streaming: false,
                 live_stream_url: String::new(),
                 live_stream_start_time: String::new(),
+                live_stream_video_id: String::new(),
+                live_stream_thumbnail_url: String::new(),
+                live_stream_viewers: None,
+                live_stream_scheduled: false,
             },
             Some(meta) => LiveResponse {
-                id: String::from(CHANNEL_ID),
-                name: String::from("Buzkaa"),
+                id: channel.id.clone(),
+                name: channel.name.clone(),
                 live_stream_title: meta.title,
-                live_streaming: true,
-                live_stream_url: String::from(LIVE_URL),
+                live_streaming: !meta.scheduled,
+                live_stream_url: format!("https://www.youtube.com/watch?v={}", meta.video_id),
                 live_stream_start_time: meta.start_date.to_rfc3339(),
+                live_stream_video_id: meta.video_id,
+                live_stream_thumbnail_url: meta.thumbnail_url.unwrap_or_default(),
+                live_stream_viewers: meta.viewers,
+                live_stream_scheduled: meta.scheduled,
             },
         }
     }
 }
 
 #[derive(Clone)]
-pub struct LiveJson(Option<String>);
+pub struct LiveJson {
+    response: LiveResponse,
+    json: String,
+}
 
 impl LiveJson {
-    pub async fn memoized() -> Memoized<Self> {
-        let visitor = Arc::new(LiveVisitor::new());
-        Memoized::new(Duration::from_secs(60), move || {
-            let visitor = Arc::clone(&visitor);
-            async move {
-                debug!("Generating new live json...");
-                let result = visitor
-                    .visit()
-                    .await
-                    .map(LiveResponse::from)
-                    .and_then(|response| {
-                        serde_json::to_string(&response)
-                            .context("Could not serialize live meta response")
-                    });
-                Self(match result {
-                    Ok(json) => Some(json),
-                    Err(err) => {
-                        error!("Could not fetch live json: {err}");
-                        None
+    pub async fn memoized(
+        providers: Arc<LiveProviders>,
+        pg: Pool<Sqlite>,
+        notifier: Arc<Notifier>,
+        channel: ChannelConfig,
+    ) -> Memoized<Self> {
+        let channel = Arc::new(channel);
+        Memoized::builder(Duration::from_secs(60))
+            .hard_expiry(Duration::from_secs(10 * 60))
+            .refresh_every(Duration::from_secs(50), Duration::from_secs(10))
+            // don't hammer youtube while it keeps failing
+            .retry_backoff(Duration::from_secs(5), Duration::from_secs(5 * 60))
+            .build(move || {
+                let providers = Arc::clone(&providers);
+                let pg = Pool::clone(&pg);
+                let notifier = Arc::clone(&notifier);
+                let channel = Arc::clone(&channel);
+                async move {
+                    debug!("Generating new live json for {}...", channel.slug);
+                    let meta = providers.live_meta(&channel.id).await.with_context(|| {
+                        format!("Could not fetch live json of {}", channel.slug)
+                    })?;
+                    let streaming = meta.as_ref().filter(|meta| !meta.scheduled);
+                    if let Some(meta) = streaming {
+                        record_stream(&pg, &channel, meta).await;
                     }
-                })
+                    notifier
+                        .observe(&channel, streaming.map(LiveMeta::to_live_stream))
+                        .await;
+                    let response = LiveResponse::new(&channel, meta);
+                    let json = serde_json::to_string(&response)
+                        .context("Could not serialize live meta response")?;
+                    Ok(Self { response, json })
+                }
+            })
+            .await
+    }
+}
+
+async fn record_stream(pg: &Pool<Sqlite>, channel: &ChannelConfig, meta: &LiveMeta) {
+    let stream = SeenStream {
+        video_id: &meta.video_id,
+        channel_id: &channel.id,
+        title: &meta.title,
+        started_at: meta.start_date.to_utc(),
+        viewers: meta.viewers,
+    };
+    if let Err(err) = streams::record_stream(pg, &stream).await {
+        error!("Could not record stream of {}: {err:#}", channel.slug);
+    }
+}
+
+pub struct LiveChannel {
+    slug: String,
+    live_json: Memoized<LiveJson>,
+}
+
+/// Live status of every configured channel, each one refreshed on its own.
+pub struct LiveChannels(Vec<LiveChannel>);
+
+impl LiveChannels {
+    pub async fn memoized(
+        providers: LiveProviders,
+        pg: Pool<Sqlite>,
+        notifier: Arc<Notifier>,
+        channels: &[ChannelConfig],
+    ) -> Self {
+        let providers = Arc::new(providers);
+        let live_channels = channels.iter().map(|channel| async {
+            let live_json = LiveJson::memoized(
+                Arc::clone(&providers),
+                Pool::clone(&pg),
+                Arc::clone(&notifier),
+                channel.clone(),
+            )
+            .await;
+            LiveChannel {
+                slug: channel.slug.clone(),
+                live_json,
             }
-        })
-        .await
+        });
+        Self(future::join_all(live_channels).await)
+    }
+
+    fn get(&self, slug: &str) -> Option<&LiveChannel> {
+        self.0.iter().find(|channel| channel.slug == slug)
     }
+
+    pub async fn shutdown(&self) {
+        for channel in &self.0 {
+            channel.live_json.shutdown().await;
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LiveChannelResponse<'a> {
+    slug: &'a str,
+    #[serde(flatten)]
+    response: &'a LiveResponse,
 }
 
-#[get("/Buzkaa")]
+/// Channels live right now, in config order.
+#[get("/live")]
+pub async fn get_live_channels(
+    live_channels: web::Data<LiveChannels>,
+) -> actix_web::Result<impl Responder> {
+    let served = future::join_all(live_channels.0.iter().map(|channel| async {
+        channel
+            .live_json
+            .get()
+            .await
+            .map(|live_json| (channel.slug.as_str(), live_json))
+    }))
+    .await;
+    let age = served
+        .iter()
+        .flatten()
+        .map(|(_, live_json)| live_json.age.as_secs())
+        .max()
+        .unwrap_or_default();
+    let live_now = served
+        .iter()
+        .flatten()
+        .filter(|(_, live_json)| live_json.value.response.live_streaming)
+        .map(|(slug, live_json)| LiveChannelResponse {
+            slug,
+            response: &live_json.value.response,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().insert_header((AGE, age)).json(live_now))
+}
+
+#[get("/{slug}")]
 pub async fn live(
     req: HttpRequest,
+    slug: web::Path<String>,
     online_users: OnlineUsersData,
-    live_json: web::Data<Memoized<LiveJson>>,
+    live_channels: web::Data<LiveChannels>,
 ) -> actix_web::Result<impl Responder> {
+    let channel = live_channels
+        .get(&slug)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Unknown channel"))?;
     let ip = req
         .connection_info()
         .realip_remote_addr()
         .expect("Could not get real ip.")
         .to_string();
+    let version = req
+        .headers()
+        .get("X-Client-Version")
+        .and_then(|version| version.to_str().ok()?.parse().ok());
     online_users
         .lock()
         .expect("Online users poisoned!")
-        .keep_alive(ip);
-    let live_meta =
-        live_json.get().await.0.ok_or_else(|| {
+        .keep_alive(ip, version);
+    let live_json =
+        channel.live_json.get().await.ok_or_else(|| {
             actix_web::error::ErrorInternalServerError("Could not get live metadata")
         })?;
     Ok(HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(live_meta))
+        .insert_header(live_json.age_header())
+        .body(live_json.value.json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    struct FakeProvider {
+        /// `Err` makes the provider fail.
+        result: Result<Option<&'static str>, ()>,
+        calls: Arc<AtomicU32>,
+    }
+
+    impl LiveProvider for FakeProvider {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn live_meta<'a>(&'a self, _channel_id: &'a str) -> LiveFuture<'a> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                let title = self.result.map_err(|_| anyhow!("fake failure"))?;
+                Ok(title.map(|title| LiveMeta {
+                    video_id: String::from("video"),
+                    title: String::from(title),
+                    start_date: DateTime::parse_from_rfc3339("2026-10-17T10:00:00Z").unwrap(),
+                    thumbnail_url: None,
+                    viewers: None,
+                    scheduled: false,
+                }))
+            })
+        }
+    }
+
+    fn fake(result: Result<Option<&'static str>, ()>) -> (Box<dyn LiveProvider>, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let provider = FakeProvider {
+            result,
+            calls: Arc::clone(&calls),
+        };
+        (Box::new(provider), calls)
+    }
+
+    #[test]
+    pub fn test_live_response() {
+        let channel = ChannelConfig {
+            id: String::from("UCL1s7OtDPaX3SdhW5433PRw"),
+            name: String::from("Buzkaa"),
+            slug: String::from("Buzkaa"),
+        };
+        let meta = LiveMeta {
+            video_id: String::from("bKqZ4nQ2x7w"),
+            title: String::from("stream"),
+            start_date: DateTime::parse_from_rfc3339("2099-01-01T10:00:00Z").unwrap(),
+            thumbnail_url: None,
+            viewers: None,
+            scheduled: true,
+        };
+        let response = LiveResponse::new(&channel, Some(meta.clone()));
+        assert!(!response.live_streaming);
+        assert!(response.live_stream_scheduled);
+        assert_eq!(
+            response.live_stream_url,
+            "https://www.youtube.com/watch?v=bKqZ4nQ2x7w"
+        );
+        let response = LiveResponse::new(
+            &channel,
+            Some(LiveMeta {
+                scheduled: false,
+                viewers: Some(214),
+                ..meta
+            }),
+        );
+        assert!(response.live_streaming);
+        assert_eq!(response.live_stream_viewers, Some(214));
+        let response = LiveResponse::new(&channel, None);
+        assert!(!response.live_streaming);
+        assert!(response.live_stream_video_id.is_empty());
+    }
+
+    #[actix_web::test]
+    pub async fn test_provider_fallback() {
+        let (failing, failing_calls) = fake(Err(()));
+        let (offline, offline_calls) = fake(Ok(None));
+        let (streaming, streaming_calls) = fake(Ok(Some("stream")));
+        let providers = LiveProviders(vec![failing, offline, streaming]);
+        assert!(providers.live_meta("channel").await.unwrap().is_none());
+        assert_eq!(failing_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(offline_calls.load(Ordering::SeqCst), 1);
+        // not live is an answer too
+        assert_eq!(streaming_calls.load(Ordering::SeqCst), 0);
+
+        let (failing, _) = fake(Err(()));
+        let (streaming, _) = fake(Ok(Some("stream")));
+        let providers = LiveProviders(vec![failing, streaming]);
+        let meta = providers.live_meta("channel").await.unwrap().unwrap();
+        assert_eq!(meta.title, "stream");
+
+        let (failing, _) = fake(Err(()));
+        let providers = LiveProviders(vec![failing]);
+        assert!(providers.live_meta("channel").await.is_err());
+    }
 }
@@ -0,0 +1,438 @@
+use super::{LiveFuture, LiveMeta, LiveProvider};
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upcoming streams this late are treated as abandoned.
+const ABANDONED_AFTER_HOURS: i64 = 6;
+
+/// Quota units a project gets per day.
+pub const DAILY_QUOTA: u64 = 10_000;
+/// Quota units one visit costs at most: searching live streams (100) and listing the recent
+/// uploads (1), each followed by listing the found videos (1).
+const VISIT_COST: u64 = 100 + 1 + 2;
+
+/// Quota units needed per day to visit `channels` channels every `interval`.
+pub fn daily_cost(channels: usize, interval: Duration) -> u64 {
+    let visits_per_channel = (24 * 60 * 60_u64).div_ceil(interval.as_secs().max(1));
+    channels as u64 * visits_per_channel * VISIT_COST
+}
+
+/// Official YouTube Data API. Searching costs 100 of the 10k daily quota units, so it's better
+/// suited as a fallback than as the first provider. Each channel is visited at most once per
+/// `min_interval`, the last answer is returned in between.
+pub struct DataApi {
+    base_url: String,
+    key: String,
+    min_interval: Duration,
+    /// When each channel was last visited and what was found.
+    last_visits: Mutex<HashMap<String, (Instant, Option<LiveMeta>)>>,
+}
+
+#[derive(Deserialize)]
+struct ListResponse<T> {
+    items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    id: SearchResultId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResultId {
+    video_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItem {
+    content_details: PlaylistItemContentDetails,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItemContentDetails {
+    video_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Video {
+    id: String,
+    snippet: VideoSnippet,
+    live_streaming_details: Option<LiveStreamingDetails>,
+}
+
+#[derive(Deserialize)]
+struct VideoSnippet {
+    title: String,
+    #[serde(default)]
+    thumbnails: HashMap<String, Thumbnail>,
+}
+
+#[derive(Deserialize)]
+struct Thumbnail {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LiveStreamingDetails {
+    actual_start_time: Option<DateTime<FixedOffset>>,
+    actual_end_time: Option<DateTime<FixedOffset>>,
+    scheduled_start_time: Option<DateTime<FixedOffset>>,
+    /// A number, but sent as a string.
+    concurrent_viewers: Option<String>,
+}
+
+impl Video {
+    fn into_live_meta(self) -> Option<LiveMeta> {
+        let details = self.live_streaming_details?;
+        let (start_date, scheduled) = match details {
+            LiveStreamingDetails {
+                actual_end_time: Some(_),
+                ..
+            } => return None,
+            LiveStreamingDetails {
+                actual_start_time: Some(start_date),
+                ..
+            } => (start_date, false),
+            LiveStreamingDetails {
+                scheduled_start_time: Some(start_date),
+                ..
+            } => (start_date, true),
+            _ => return None,
+        };
+        let thumbnail_url = ["maxres", "high", "medium", "default"]
+            .iter()
+            .find_map(|size| self.snippet.thumbnails.get(*size))
+            .map(|thumbnail| thumbnail.url.clone());
+        Some(LiveMeta {
+            video_id: self.id,
+            title: self.snippet.title,
+            start_date,
+            thumbnail_url,
+            viewers: details
+                .concurrent_viewers
+                .and_then(|viewers| viewers.parse().ok()),
+            scheduled,
+        })
+    }
+}
+
+impl DataApi {
+    /// `base_url` is `https://www.googleapis.com/youtube/v3` outside of tests.
+    pub fn new(base_url: String, key: String, min_interval: Duration) -> Self {
+        Self {
+            base_url,
+            key,
+            min_interval,
+            last_visits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn visit_throttled(&self, channel_id: &str) -> anyhow::Result<Option<LiveMeta>> {
+        if let Some((visited_at, meta)) = self
+            .last_visits
+            .lock()
+            .expect("Last visits poisoned!")
+            .get(channel_id)
+        {
+            if visited_at.elapsed() < self.min_interval {
+                return Ok(meta.clone());
+            }
+        }
+        let meta = self.visit(channel_id).await?;
+        self.last_visits
+            .lock()
+            .expect("Last visits poisoned!")
+            .insert(channel_id.to_owned(), (Instant::now(), meta.clone()));
+        Ok(meta)
+    }
+
+    async fn visit(&self, channel_id: &str) -> anyhow::Result<Option<LiveMeta>> {
+        let live_ids = self.search_live(channel_id).await?;
+        if let Some(meta) = self
+            .find_stream(&live_ids)
+            .await
+            .context("Could not get live videos")?
+        {
+            return Ok(Some(meta));
+        }
+        // searching upcoming streams would cost another 100 units, the uploads list them too
+        let upload_ids = self.recent_uploads(channel_id).await?;
+        self.find_stream(&upload_ids)
+            .await
+            .context("Could not get uploaded videos")
+    }
+
+    async fn search_live(&self, channel_id: &str) -> anyhow::Result<Vec<String>> {
+        let search: ListResponse<SearchResult> = self
+            .get(
+                "search",
+                &[
+                    ("part", "id"),
+                    ("channelId", channel_id),
+                    ("eventType", "live"),
+                    ("type", "video"),
+                    ("maxResults", "5"),
+                ],
+            )
+            .await
+            .context("Could not search live videos")?;
+        Ok(search
+            .items
+            .into_iter()
+            .map(|result| result.id.video_id)
+            .collect())
+    }
+
+    async fn recent_uploads(&self, channel_id: &str) -> anyhow::Result<Vec<String>> {
+        // the uploads playlist id is the channel id with UU instead of UC
+        let playlist_id = channel_id
+            .strip_prefix("UC")
+            .map(|id| format!("UU{id}"))
+            .with_context(|| format!("{channel_id} is not a channel id"))?;
+        let playlist: ListResponse<PlaylistItem> = self
+            .get(
+                "playlistItems",
+                &[
+                    ("part", "contentDetails"),
+                    ("playlistId", &playlist_id),
+                    ("maxResults", "10"),
+                ],
+            )
+            .await
+            .context("Could not list uploaded videos")?;
+        Ok(playlist
+            .items
+            .into_iter()
+            .map(|item| item.content_details.video_id)
+            .collect())
+    }
+
+    /// Live stream among the videos, or the upcoming one starting first.
+    async fn find_stream(&self, video_ids: &[String]) -> anyhow::Result<Option<LiveMeta>> {
+        if video_ids.is_empty() {
+            return Ok(None);
+        }
+        let videos: ListResponse<Video> = self
+            .get(
+                "videos",
+                &[
+                    ("part", "snippet,liveStreamingDetails"),
+                    ("id", &video_ids.join(",")),
+                ],
+            )
+            .await?;
+        // search results lag behind, streams may have ended or started already. Streams
+        // scheduled long ago that never started stay upcoming forever.
+        let abandoned_before = Utc::now() - TimeDelta::hours(ABANDONED_AFTER_HOURS);
+        let meta = videos
+            .items
+            .into_iter()
+            .filter_map(Video::into_live_meta)
+            .filter(|meta| !meta.scheduled || meta.start_date > abandoned_before)
+            .min_by_key(|meta| (meta.scheduled, meta.start_date));
+        if let Some(meta) = &meta {
+            debug!(
+                "Stream found with data api. Title: '{}', start date: '{}', scheduled: {}",
+                meta.title, meta.start_date, meta.scheduled
+            );
+        }
+        Ok(meta)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> anyhow::Result<T> {
+        let mut query = params.to_vec();
+        query.push(("key", &self.key));
+        let mut res = awc::Client::default()
+            .get(format!("{}/{endpoint}", self.base_url))
+            .query(&query)
+            .context("Could not encode query")?
+            .send()
+            .await
+            .map_err(|err| anyhow!("Could not call data api: {}", err))?;
+        if !res.status().is_success() {
+            bail!("Data api responded with {}", res.status());
+        }
+        res.json().await.context("Could not read data api response")
+    }
+}
+
+impl LiveProvider for DataApi {
+    fn name(&self) -> &'static str {
+        "data_api"
+    }
+
+    fn live_meta<'a>(&'a self, channel_id: &'a str) -> LiveFuture<'a> {
+        Box::pin(self.visit_throttled(channel_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::spawn;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Params = web::Query<HashMap<String, String>>;
+
+    async fn search(params: Params, searches: web::Data<AtomicUsize>) -> HttpResponse {
+        assert_eq!(params["key"], "key");
+        assert_eq!(params["eventType"], "live");
+        searches.fetch_add(1, Ordering::SeqCst);
+        let video_ids: &[&str] = match params["channelId"].as_str() {
+            "UC-live" => &["live-video"],
+            "UC-ended" => &["ended-video"],
+            "UC-quota" => return HttpResponse::Forbidden().finish(),
+            _ => &[],
+        };
+        let items = video_ids
+            .iter()
+            .map(|id| json!({ "id": { "videoId": id } }))
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(json!({ "items": items }))
+    }
+
+    async fn playlist_items(params: Params) -> HttpResponse {
+        assert_eq!(params["key"], "key");
+        let video_ids: &[&str] = match params["playlistId"].as_str() {
+            "UU-upcoming" => &["vod-video", "abandoned-video", "later-video", "soon-video"],
+            "UU-ended" => &["ended-video", "vod-video"],
+            _ => &[],
+        };
+        let items = video_ids
+            .iter()
+            .map(|id| json!({ "contentDetails": { "videoId": id } }))
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(json!({ "items": items }))
+    }
+
+    async fn videos(params: Params) -> HttpResponse {
+        let items = params["id"]
+            .split(',')
+            .map(|id| {
+                let details = match id {
+                    "live-video" => json!({
+                        "actualStartTime": "2026-10-17T10:00:00Z",
+                        "concurrentViewers": "214",
+                    }),
+                    "ended-video" => json!({
+                        "actualStartTime": "2026-10-17T10:00:00Z",
+                        "actualEndTime": "2026-10-17T12:00:00Z",
+                    }),
+                    "abandoned-video" => json!({ "scheduledStartTime": "2020-01-01T10:00:00Z" }),
+                    "later-video" => json!({ "scheduledStartTime": "2099-01-02T10:00:00Z" }),
+                    "vod-video" => json!(null),
+                    _ => json!({ "scheduledStartTime": "2099-01-01T10:00:00Z" }),
+                };
+                json!({
+                    "id": id,
+                    "snippet": {
+                        "title": "stream",
+                        "thumbnails": {
+                            "default": { "url": format!("https://i.ytimg.com/vi/{id}/default.jpg") },
+                            "high": { "url": format!("https://i.ytimg.com/vi/{id}/hqdefault.jpg") },
+                        },
+                    },
+                    "liveStreamingDetails": details,
+                })
+            })
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(json!({ "items": items }))
+    }
+
+    fn start_server() -> (String, web::Data<AtomicUsize>) {
+        let searches = web::Data::new(AtomicUsize::new(0));
+        let server = {
+            let searches = web::Data::clone(&searches);
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::clone(&searches))
+                    .route("/search", web::get().to(search))
+                    .route("/playlistItems", web::get().to(playlist_items))
+                    .route("/videos", web::get().to(videos))
+            })
+        }
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        spawn(server.run());
+        (format!("http://{addr}"), searches)
+    }
+
+    #[actix_web::test]
+    pub async fn test_data_api() {
+        let (base_url, _) = start_server();
+        let api = DataApi::new(base_url, String::from("key"), Duration::ZERO);
+
+        let meta = api.visit("UC-live").await.unwrap().unwrap();
+        assert_eq!(meta.video_id, "live-video");
+        assert_eq!(meta.title, "stream");
+        assert_eq!(meta.start_date.to_rfc3339(), "2026-10-17T10:00:00+00:00");
+        assert_eq!(
+            meta.thumbnail_url.as_deref(),
+            Some("https://i.ytimg.com/vi/live-video/hqdefault.jpg")
+        );
+        assert_eq!(meta.viewers, Some(214));
+        assert!(!meta.scheduled);
+        let meta = api.visit("UC-upcoming").await.unwrap().unwrap();
+        assert_eq!(meta.video_id, "soon-video");
+        assert!(meta.scheduled);
+        assert!(api.visit("UC-ended").await.unwrap().is_none());
+        assert!(api.visit("UC-offline").await.unwrap().is_none());
+        assert!(api.visit("UC-quota").await.is_err());
+        assert!(api.visit("offline").await.is_err());
+    }
+
+    #[actix_web::test]
+    pub async fn test_min_interval() {
+        let (base_url, searches) = start_server();
+        let api = DataApi::new(base_url, String::from("key"), Duration::from_secs(60 * 60));
+
+        let meta = api.live_meta("UC-live").await.unwrap().unwrap();
+        assert_eq!(meta.video_id, "live-video");
+        assert_eq!(searches.load(Ordering::SeqCst), 1);
+        let meta = api.live_meta("UC-live").await.unwrap().unwrap();
+        assert_eq!(meta.video_id, "live-video");
+        assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+        // a single search while nothing is live too
+        let meta = api.live_meta("UC-upcoming").await.unwrap().unwrap();
+        assert_eq!(meta.video_id, "soon-video");
+        assert_eq!(searches.load(Ordering::SeqCst), 2);
+        assert!(api.live_meta("UC-offline").await.unwrap().is_none());
+        assert_eq!(searches.load(Ordering::SeqCst), 3);
+        assert!(api.live_meta("UC-offline").await.unwrap().is_none());
+        assert_eq!(searches.load(Ordering::SeqCst), 3);
+
+        // failures aren't remembered
+        assert!(api.live_meta("UC-quota").await.is_err());
+        assert!(api.live_meta("UC-quota").await.is_err());
+        assert_eq!(searches.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    pub fn test_daily_cost() {
+        assert_eq!(daily_cost(1, Duration::from_secs(15 * 60)), 96 * 103);
+        assert!(daily_cost(1, Duration::from_secs(15 * 60)) <= DAILY_QUOTA);
+        assert!(daily_cost(2, Duration::from_secs(15 * 60)) > DAILY_QUOTA);
+        assert!(daily_cost(2, Duration::from_secs(30 * 60)) <= DAILY_QUOTA);
+        assert!(daily_cost(1, Duration::from_secs(50)) > DAILY_QUOTA);
+    }
+}
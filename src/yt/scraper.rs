@@ -0,0 +1,338 @@
+use super::{LiveFuture, LiveMeta, LiveProvider};
+use anyhow::{anyhow, bail, Context};
+use awc::http::Uri;
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use scraper::{Html, Selector};
+
+/// Scrapes the channel's `/live` page, which points at the stream while the channel is live or has
+/// one scheduled.
+pub struct Scraper {
+    base_url: String,
+    name_sel: Selector,
+    video_id_sel: Selector,
+    thumbnail_sel: Selector,
+    start_date_sel: Selector,
+    end_date_sel: Selector,
+    canonical_sel: Selector,
+    consent_sel: Selector,
+}
+
+impl Scraper {
+    /// `base_url` is `https://www.youtube.com` outside of tests.
+    pub fn new(base_url: String) -> Self {
+        let name_sel = Selector::parse(r#"#watch7-content > meta[itemprop="name"]"#)
+            .expect("Invalid name selector");
+        let video_id_sel = Selector::parse(r#"#watch7-content > meta[itemprop="videoId"]"#)
+            .expect("Invalid video id selector");
+        let thumbnail_sel = Selector::parse(r#"#watch7-content > link[itemprop="thumbnailUrl"]"#)
+            .expect("Invalid thumbnail selector");
+        let start_date_sel = Selector::parse(r#"#watch7-content > * > meta[itemprop="startDate"]"#)
+            .expect("Invalid name selector");
+        let end_date_sel = Selector::parse(r#"#watch7-content > * > meta[itemprop="endDate"]"#)
+            .expect("Invalid end date selector");
+        let canonical_sel =
+            Selector::parse(r#"link[rel="canonical"]"#).expect("Invalid canonical selector");
+        let consent_sel = Selector::parse(r#"form[action^="https://consent.youtube.com"]"#)
+            .expect("Invalid consent selector");
+        Self {
+            base_url,
+            name_sel,
+            video_id_sel,
+            thumbnail_sel,
+            start_date_sel,
+            end_date_sel,
+            canonical_sel,
+            consent_sel,
+        }
+    }
+
+    async fn visit(&self, channel_id: &str) -> anyhow::Result<Option<LiveMeta>> {
+        let channel_page = self
+            .fetch(&format!("{}/channel/{channel_id}/live", self.base_url))
+            .await
+            .context("Could not visit youtube channel/live page")?;
+        let live_path = match self
+            .parse_live_path(&channel_page)
+            .context("Could not get live url")?
+        {
+            None => return Ok(None),
+            Some(path) => path,
+        };
+        let live_url = format!("{}{live_path}", self.base_url);
+        info!("Live url: {live_url}");
+        let live_page = self
+            .fetch(&live_url)
+            .await
+            .context("Could not visit youtube live page")?;
+        self.parse_live_meta(&live_page)
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<String> {
+        let client = Self::get_awc();
+        let mut res = client
+            .get(url)
+            .send()
+            .await
+            .map_err(|err| anyhow!("Could not send request: {}", err))?;
+        if !res.status().is_success() {
+            bail!("Youtube responded with {}", res.status());
+        }
+        let body = res.body().await.context("Could not read body")?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Path of the page the channel's `/live` page points at, the stream while it's live.
+    fn parse_live_path(&self, html: &str) -> anyhow::Result<Option<String>> {
+        let document = Html::parse_document(html);
+        self.check_consent(&document)?;
+        let url_element = match document.select(&self.canonical_sel).next() {
+            None => return Ok(None),
+            Some(element) => element,
+        };
+        let url = url_element
+            .value()
+            .attr("href")
+            .context("Could not select canonical href!")?;
+        // the canonical url always points at youtube.com, keep the path only
+        let url: Uri = url.parse().context("Invalid canonical href")?;
+        let path = url
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        Ok(Some(path.to_owned()))
+    }
+
+    // aint gonna pay for api
+    fn parse_live_meta(&self, html: &str) -> anyhow::Result<Option<LiveMeta>> {
+        let document = Html::parse_document(html);
+        self.check_consent(&document)?;
+        let title = match document.select(&self.name_sel).next() {
+            None => return Ok(None),
+            Some(element) => element
+                .value()
+                .attr("content")
+                .context("Could not select title content")?
+                .to_owned(),
+        };
+        let start_date_raw = match document.select(&self.start_date_sel).next() {
+            None => return Ok(None),
+            Some(element) => element
+                .value()
+                .attr("content")
+                .context("Could not select start date content")?,
+        };
+        let start_date =
+            DateTime::parse_from_rfc3339(start_date_raw).context("Could not parse start date!")?;
+        // ended streams keep their start date
+        if document.select(&self.end_date_sel).next().is_some() {
+            debug!("Stream '{title}' has ended, start date: '{start_date_raw}'");
+            return Ok(None);
+        }
+        let video_id = document
+            .select(&self.video_id_sel)
+            .next()
+            .and_then(|element| element.value().attr("content"))
+            .context("Could not select video id")?
+            .to_owned();
+        let thumbnail_url = document
+            .select(&self.thumbnail_sel)
+            .next()
+            .and_then(|element| element.value().attr("href"))
+            .map(String::from);
+        // upcoming streams have the scheduled start date, which passes while the stream waits for
+        // the streamer
+        let scheduled = match parse_is_live(html) {
+            Some(is_live) => !is_live,
+            None => start_date > Utc::now(),
+        };
+        let viewers = if scheduled { None } else { parse_viewers(html) };
+        debug!(
+            "Live stream scrapped. Title: '{title}', start date: '{start_date_raw}', \
+             scheduled: {scheduled}"
+        );
+        Ok(Some(LiveMeta {
+            video_id,
+            title,
+            start_date,
+            thumbnail_url,
+            viewers,
+            scheduled,
+        }))
+    }
+
+    /// The consent wall has no stream data, so it would look like the channel isn't live.
+    fn check_consent(&self, document: &Html) -> anyhow::Result<()> {
+        if document.select(&self.consent_sel).next().is_some() {
+            bail!("Got the consent wall instead of the page");
+        }
+        Ok(())
+    }
+
+    fn get_awc() -> awc::Client {
+        awc::Client::builder()
+            .add_default_header(("user-agent", "curl")) // possibly provides GPDR bypass?
+            .finish()
+    }
+}
+
+/// View count renderer from the page's `ytInitialData`, whether the stream is live and how many
+/// are watching isn't in the markup.
+fn view_count_renderer(html: &str) -> Option<&str> {
+    Some(&html[html.find(r#""videoViewCountRenderer":"#)?..])
+}
+
+/// Whether the stream is live rather than waiting to start.
+fn parse_is_live(html: &str) -> Option<bool> {
+    const IS_LIVE_PREFIX: &str = r#""isLive":"#;
+    let renderer = view_count_renderer(html)?;
+    let is_live = &renderer[renderer.find(IS_LIVE_PREFIX)? + IS_LIVE_PREFIX.len()..];
+    if is_live.starts_with("true") {
+        Some(true)
+    } else if is_live.starts_with("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Concurrent viewers.
+fn parse_viewers(html: &str) -> Option<u32> {
+    const COUNT_PREFIX: &str = r#""originalViewCount":""#;
+    let renderer = view_count_renderer(html)?;
+    let count = &renderer[renderer.find(COUNT_PREFIX)? + COUNT_PREFIX.len()..];
+    count[..count.find('"')?].parse().ok()
+}
+
+impl LiveProvider for Scraper {
+    fn name(&self) -> &'static str {
+        "scraper"
+    }
+
+    fn live_meta<'a>(&'a self, channel_id: &'a str) -> LiveFuture<'a> {
+        Box::pin(self.visit(channel_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::spawn;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde::Deserialize;
+
+    const LIVE: &str = include_str!("fixtures/live.html");
+    const UPCOMING: &str = include_str!("fixtures/upcoming.html");
+    /// Scheduled for a time that has passed, still waiting for the streamer.
+    const WAITING: &str = include_str!("fixtures/waiting.html");
+    const ENDED: &str = include_str!("fixtures/ended.html");
+    const OFFLINE: &str = include_str!("fixtures/offline.html");
+    const CONSENT: &str = include_str!("fixtures/consent.html");
+
+    fn scraper() -> Scraper {
+        Scraper::new(String::from("http://localhost"))
+    }
+
+    #[test]
+    pub fn test_parse_live_path() {
+        let scraper = scraper();
+        assert_eq!(
+            scraper.parse_live_path(LIVE).unwrap().as_deref(),
+            Some("/watch?v=bKqZ4nQ2x7w")
+        );
+        assert_eq!(
+            scraper.parse_live_path(OFFLINE).unwrap().as_deref(),
+            Some("/channel/UCL1s7OtDPaX3SdhW5433PRw")
+        );
+        assert!(scraper.parse_live_path(CONSENT).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_live_meta() {
+        let scraper = scraper();
+        let meta = scraper.parse_live_meta(LIVE).unwrap().unwrap();
+        assert_eq!(meta.video_id, "bKqZ4nQ2x7w");
+        assert_eq!(meta.title, "Kopiemy kamienie z widzami!");
+        assert_eq!(meta.start_date.to_rfc3339(), "2026-10-12T18:00:04+02:00");
+        assert_eq!(
+            meta.thumbnail_url.as_deref(),
+            Some("https://i.ytimg.com/vi/bKqZ4nQ2x7w/maxresdefault_live.jpg")
+        );
+        assert_eq!(meta.viewers, Some(214));
+        assert!(!meta.scheduled);
+        let meta = scraper.parse_live_meta(UPCOMING).unwrap().unwrap();
+        assert_eq!(meta.video_id, "Xw3PqL0aZ9c");
+        assert_eq!(meta.start_date.to_rfc3339(), "2099-01-01T20:00:00+01:00");
+        assert_eq!(meta.viewers, None);
+        assert!(meta.scheduled);
+        let meta = scraper.parse_live_meta(WAITING).unwrap().unwrap();
+        assert_eq!(meta.video_id, "Qm7Tz4WcL2s");
+        assert_eq!(meta.start_date.to_rfc3339(), "2026-10-13T18:00:00+02:00");
+        assert_eq!(meta.viewers, None);
+        assert!(meta.scheduled);
+        assert!(scraper.parse_live_meta(ENDED).unwrap().is_none());
+        assert!(scraper.parse_live_meta(OFFLINE).unwrap().is_none());
+        assert!(scraper.parse_live_meta(CONSENT).is_err());
+    }
+
+    fn html(body: &'static str) -> HttpResponse {
+        HttpResponse::Ok().content_type("text/html").body(body)
+    }
+
+    /// Channel ids name the fixture their `/live` page serves.
+    async fn channel_live(channel_id: web::Path<String>) -> HttpResponse {
+        match channel_id.as_str() {
+            "live" => html(LIVE),
+            "upcoming" => html(UPCOMING),
+            "waiting" => html(WAITING),
+            "ended" => html(ENDED),
+            "consent" => html(CONSENT),
+            "UCL1s7OtDPaX3SdhW5433PRw" => html(OFFLINE),
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    async fn channel() -> HttpResponse {
+        html(OFFLINE)
+    }
+
+    #[derive(Deserialize)]
+    struct WatchQuery {
+        v: String,
+    }
+
+    async fn watch(query: web::Query<WatchQuery>) -> HttpResponse {
+        match query.v.as_str() {
+            "bKqZ4nQ2x7w" => html(LIVE),
+            "Xw3PqL0aZ9c" => html(UPCOMING),
+            "Qm7Tz4WcL2s" => html(WAITING),
+            "Tn8vR2kEo1M" => html(ENDED),
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    #[actix_web::test]
+    pub async fn test_scrape_local_youtube() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/channel/{id}/live", web::get().to(channel_live))
+                .route("/channel/{id}", web::get().to(channel))
+                .route("/watch", web::get().to(watch))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        spawn(server.run());
+        let scraper = Scraper::new(format!("http://{addr}"));
+
+        let meta = scraper.visit("live").await.unwrap().unwrap();
+        assert_eq!(meta.title, "Kopiemy kamienie z widzami!");
+        assert!(scraper.visit("upcoming").await.unwrap().unwrap().scheduled);
+        assert!(scraper.visit("waiting").await.unwrap().unwrap().scheduled);
+        for channel_id in ["ended", "UCL1s7OtDPaX3SdhW5433PRw"] {
+            assert!(scraper.visit(channel_id).await.unwrap().is_none());
+        }
+        assert!(scraper.visit("consent").await.is_err());
+        assert!(scraper.visit("missing").await.is_err());
+    }
+}